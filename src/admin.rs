@@ -0,0 +1,193 @@
+//! Endpoints for operators, authenticated with the `ADMIN_TOKEN` as bearer token
+//!
+//! Without a configured token, all admin routes answer 404. Errors have the shape of
+//! scrape errors but their own codes, as they are not about scraping.
+
+use axum::{
+    extract::Query,
+    http::{self, Request},
+    middleware::Next,
+    response,
+    routing::get,
+    Extension, Router,
+};
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+
+use scraper::ScrapeResultError;
+
+use crate::web::{cache_key, json_response};
+use crate::State;
+
+pub fn router(state: Arc<State>) -> Router {
+    Router::new()
+        .route("/admin/cache", get(lookup).delete(invalidate))
+        .route("/admin/cache/stats", get(stats))
+        .layer(axum::middleware::from_fn(move |req, next| {
+            let state = state.clone();
+            auth(req, state, next)
+        }))
+}
+
+pub async fn auth<B>(
+    req: Request<B>,
+    state: Arc<State>,
+    next: Next<B>,
+) -> std::result::Result<response::Response, http::StatusCode> {
+    let token = match &state.config.admin_token {
+        Some(token) => token,
+        None => return Err(http::StatusCode::NOT_FOUND),
+    };
+    let given = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "));
+    match given {
+        Some(given) if bool::from(given.as_bytes().ct_eq(token.as_bytes())) => {
+            Ok(next.run(req).await)
+        }
+        _ => Err(http::StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// Selects cache entries, exactly one field must be set
+#[derive(serde::Deserialize)]
+pub struct CacheQuery {
+    url: Option<String>,
+    /// Matches the host and all its subdomains
+    host: Option<String>,
+    prefix: Option<String>,
+}
+
+/// Error of an admin route
+#[derive(serde::Serialize)]
+struct AdminError {
+    errors: Vec<String>,
+    code: &'static str,
+}
+
+fn admin_error(
+    status: http::StatusCode,
+    code: &'static str,
+    message: &str,
+) -> response::Response<String> {
+    json_response(
+        status,
+        &AdminError {
+            errors: vec![message.to_string()],
+            code,
+        },
+    )
+}
+
+fn bad_request(message: &str) -> response::Response<String> {
+    admin_error(http::StatusCode::BAD_REQUEST, "invalid_query", message)
+}
+
+pub async fn lookup(
+    Query(query): Query<CacheQuery>,
+    Extension(state): Extension<Arc<State>>,
+) -> response::Response<String> {
+    let url = match query.url {
+        Some(url) => url,
+        None => return bad_request("url is required"),
+    };
+    match state.result_cache.lookup(&cache_key(&state, url)) {
+        Some(stored) => json_response(http::StatusCode::OK, &stored),
+        None => admin_error(
+            http::StatusCode::NOT_FOUND,
+            "not_cached",
+            "URL is not cached",
+        ),
+    }
+}
+
+pub async fn invalidate(
+    Query(query): Query<CacheQuery>,
+    Extension(state): Extension<Arc<State>>,
+) -> response::Response<String> {
+    let cache = &state.result_cache;
+    let res = match (query.url, query.host, query.prefix) {
+        (Some(url), None, None) => cache.invalidate(&cache_key(&state, url)).await,
+        (None, Some(host), None) => {
+            let host = host.to_ascii_lowercase();
+            cache.invalidate_if(|url| is_on_host(url, &host)).await
+        }
+        (None, None, Some(prefix)) => cache.invalidate_if(|url| url.starts_with(&prefix)).await,
+        _ => return bad_request("exactly one of url, host or prefix is required"),
+    };
+    match res {
+        Ok(invalidated) => {
+            log::info!("invalidated {:?} cache entries", invalidated);
+            json_response(http::StatusCode::OK, &invalidated)
+        }
+        Err(e) => {
+            log::error!("could not invalidate cache: {:?}", e);
+            json_response(
+                http::StatusCode::INTERNAL_SERVER_ERROR,
+                &ScrapeResultError::from_error(&e),
+            )
+        }
+    }
+}
+
+pub async fn stats(Extension(state): Extension<Arc<State>>) -> response::Response<String> {
+    json_response(http::StatusCode::OK, &state.result_cache.stats())
+}
+
+fn is_on_host(url: &str, host: &str) -> bool {
+    let url = match url::Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    match url.host_str() {
+        Some(x) => x == host || x.ends_with(&format!(".{}", host)),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_admin_errors() -> anyhow::Result<()> {
+        let state = Arc::new(State::new(crate::Configuration::default())?);
+        let query = |url: Option<&str>| CacheQuery {
+            url: url.map(str::to_string),
+            host: None,
+            prefix: None,
+        };
+        let res = tokio_test::block_on(lookup(Query(query(None)), Extension(state.clone())));
+        assert_eq!(http::StatusCode::BAD_REQUEST, res.status());
+        assert_eq!(
+            r#"{"errors":["url is required"],"code":"invalid_query"}"#,
+            res.body()
+        );
+        let res = tokio_test::block_on(lookup(
+            Query(query(Some("https://example.com/a.png"))),
+            Extension(state),
+        ));
+        assert_eq!(http::StatusCode::NOT_FOUND, res.status());
+        assert_eq!(
+            r#"{"errors":["URL is not cached"],"code":"not_cached"}"#,
+            res.body()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_on_host() {
+        assert!(is_on_host("https://deviantart.com/art/1", "deviantart.com"));
+        assert!(is_on_host(
+            "https://www.deviantart.com/art/1",
+            "deviantart.com"
+        ));
+        assert!(!is_on_host(
+            "https://notdeviantart.com/art/1",
+            "deviantart.com"
+        ));
+        assert!(!is_on_host("not a url", "deviantart.com"));
+    }
+}
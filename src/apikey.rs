@@ -0,0 +1,224 @@
+//! API keys for server to server clients
+//!
+//! Keys are configured via `API_KEYS` and `API_KEY_FILE` as `name:key` or
+//! `name:key:per_minute` entries, separated by commas or newlines respectively, and
+//! sent as `Authorization: Bearer <key>`. As `:` separates the fields, neither names
+//! nor keys may contain it, and keys in `API_KEYS` may not contain commas. Requests
+//! without a key are handled as before, requests with an unknown key are refused.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use axum::{
+    body::Body,
+    http::{self, Request},
+    middleware::Next,
+    response::{self, IntoResponse},
+};
+use log::{debug, info};
+use subtle::ConstantTimeEq;
+
+use scraper::{ErrorKind, ScrapeResultError};
+
+use crate::web::json_response;
+use crate::{Configuration, State};
+
+struct Key {
+    name: String,
+    secret: String,
+    /// Requests per minute, 0 for no limit
+    per_minute: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Client authenticated by its API key, available as request extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiClient {
+    pub name: String,
+}
+
+#[derive(Clone, Default)]
+pub struct Keys {
+    keys: Arc<Vec<Key>>,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl Keys {
+    pub fn new(config: &Configuration) -> Result<Self> {
+        let mut keys = Vec::new();
+        let default = config.api_key_rate_limit;
+        if let Some(list) = &config.api_keys {
+            for entry in list.split(',').filter(|x| !x.trim().is_empty()) {
+                keys.push(parse(entry, default).context("invalid entry in API_KEYS")?);
+            }
+        }
+        if let Some(path) = &config.api_key_file {
+            let file = std::fs::read_to_string(path)
+                .with_context(|| format!("could not read API_KEY_FILE {}", path))?;
+            for (n, line) in file.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                keys.push(
+                    parse(line, default)
+                        .with_context(|| format!("invalid entry in {} line {}", path, n + 1))?,
+                );
+            }
+        }
+        Ok(Self {
+            keys: Arc::new(keys),
+            ..Self::default()
+        })
+    }
+
+    fn find(&self, secret: &str) -> Option<&Key> {
+        self.keys
+            .iter()
+            .find(|x| bool::from(x.secret.as_bytes().ct_eq(secret.as_bytes())))
+    }
+
+    /// Takes a request from the key's budget, returns how long to wait if it is exhausted
+    fn reserve(&self, key: &Key) -> Option<Duration> {
+        if key.per_minute == 0 {
+            return None;
+        }
+        let rate = key.per_minute as f64 / 60.0;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.name.clone()).or_insert(Bucket {
+            tokens: key.per_minute as f64,
+            updated: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(key.per_minute as f64);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+fn parse(entry: &str, default_per_minute: u32) -> Result<Key> {
+    let mut parts = entry.trim().splitn(3, ':');
+    let name = parts.next().unwrap_or_default().trim();
+    let secret = parts.next().unwrap_or_default().trim();
+    if name.is_empty() || secret.is_empty() {
+        anyhow::bail!("expected name:key");
+    }
+    let per_minute = match parts.next() {
+        Some(limit) => limit.trim().parse().context("invalid rate limit")?,
+        None => default_per_minute,
+    };
+    Ok(Key {
+        name: name.to_string(),
+        secret: secret.to_string(),
+        per_minute,
+    })
+}
+
+fn refuse(status: http::StatusCode, code: ErrorKind, message: &str) -> response::Response {
+    json_response(status, &ScrapeResultError::new(code, message)).into_response()
+}
+
+pub async fn authenticate(
+    mut req: Request<Body>,
+    state: Arc<State>,
+    next: Next<Body>,
+) -> response::Response {
+    let given = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.strip_prefix("Bearer "));
+    let given = match given {
+        None => return next.run(req).await,
+        Some(Some(given)) => given,
+        Some(None) => {
+            return refuse(
+                http::StatusCode::UNAUTHORIZED,
+                ErrorKind::Unauthorized,
+                "expected a bearer token",
+            )
+        }
+    };
+    let key = match state.api_keys.find(given) {
+        Some(key) => key,
+        None => {
+            return refuse(
+                http::StatusCode::UNAUTHORIZED,
+                ErrorKind::Unauthorized,
+                "API key is invalid",
+            )
+        }
+    };
+    if let Some(wait) = state.api_keys.reserve(key) {
+        debug!("request budget of API client {} exhausted", key.name);
+        let mut res = refuse(
+            http::StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::RateLimited,
+            "request budget of the API key exhausted",
+        );
+        res.headers_mut().insert(
+            http::header::RETRY_AFTER,
+            http::HeaderValue::from(wait.as_secs_f64().ceil() as u64),
+        );
+        return res;
+    }
+    info!("request {} from API client {}", req.uri(), key.name);
+    req.extensions_mut().insert(ApiClient {
+        name: key.name.clone(),
+    });
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_keys() -> Result<()> {
+        let config = Configuration {
+            api_keys: Some("importer:secret,bot:other:0, ".to_string()),
+            api_key_rate_limit: 30,
+            ..Configuration::default()
+        };
+        let keys = Keys::new(&config)?;
+        assert_eq!(Some("importer"), keys.find("secret").map(|x| &*x.name));
+        assert_eq!(Some(30), keys.find("secret").map(|x| x.per_minute));
+        assert_eq!(Some(0), keys.find("other").map(|x| x.per_minute));
+        assert!(keys.find("secre").is_none());
+        assert!(parse("nokey", 30).is_err());
+        assert!(parse("name:key:lots", 30).is_err());
+        assert!(parse("name:se:cr:et", 30).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_budget() -> Result<()> {
+        let config = Configuration {
+            api_keys: Some("limited:secret:2,unlimited:other:0".to_string()),
+            ..Configuration::default()
+        };
+        let keys = Keys::new(&config)?;
+        let limited = keys.find("secret").unwrap();
+        assert_eq!(None, keys.reserve(limited));
+        assert_eq!(None, keys.reserve(limited));
+        let wait = keys.reserve(limited).expect("budget should be exhausted");
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+        let unlimited = keys.find("other").unwrap();
+        for _ in 0..10 {
+            assert_eq!(None, keys.reserve(unlimited));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,444 @@
+//! Cache of scrape results
+//!
+//! Results are kept in memory and, if `CACHE_DB` is set, persisted in a sled database so
+//! they survive restarts. How long a result is kept depends on its [`Outcome`].
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use scraper::{ErrorKind, ScrapeResult, ScrapeResultError};
+use serde::{Deserialize, Serialize};
+
+use crate::exporter::RESULT_CACHE;
+use crate::Configuration;
+
+/// Result of a scrape as far as the cache is concerned
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Found(ScrapeResult),
+    /// No scraper accepted the URL
+    Empty,
+    Failed(ScrapeResultError),
+}
+
+/// How long each kind of [`Outcome`] is cached, zero disables caching it
+#[derive(Debug, Clone, Copy)]
+pub struct Ttls {
+    pub found: Duration,
+    pub empty: Duration,
+    /// Errors that won't go away by retrying, ie. a deleted post
+    pub not_found: Duration,
+    pub rate_limited: Duration,
+    /// All other errors, except timeouts and internal errors which are never cached
+    pub error: Duration,
+}
+
+impl Ttls {
+    fn from_config(config: &Configuration) -> Self {
+        Self {
+            found: Duration::from_secs(config.cache_duration),
+            empty: Duration::from_secs(config.cache_empty_duration),
+            not_found: Duration::from_secs(config.cache_not_found_duration),
+            rate_limited: Duration::from_secs(config.cache_rate_limited_duration),
+            error: Duration::from_secs(config.cache_error_duration),
+        }
+    }
+
+    fn of(&self, outcome: &Outcome) -> Duration {
+        match outcome {
+            Outcome::Found(_) => self.found,
+            Outcome::Empty => self.empty,
+            Outcome::Failed(e) => match e.code {
+                ErrorKind::UpstreamNotFound | ErrorKind::InvalidUrl | ErrorKind::UnsupportedUrl => {
+                    self.not_found
+                }
+                ErrorKind::UpstreamRateLimited | ErrorKind::RateLimited => self.rate_limited,
+                ErrorKind::AuthRequired | ErrorKind::UpstreamError | ErrorKind::ParseFailed => {
+                    self.error
+                }
+                ErrorKind::Timeout
+                | ErrorKind::Forbidden
+                | ErrorKind::Unauthorized
+                | ErrorKind::NotFound
+                | ErrorKind::Internal => Duration::ZERO,
+            },
+        }
+    }
+
+    fn max(&self) -> Duration {
+        [
+            self.found,
+            self.empty,
+            self.not_found,
+            self.rate_limited,
+            self.error,
+        ]
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Stored {
+    /// Unix timestamp in seconds
+    pub expires: u64,
+    pub outcome: Outcome,
+}
+
+impl Stored {
+    fn is_fresh(&self) -> bool {
+        self.expires > now()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Stats {
+    /// Entries held in memory
+    pub entries: u64,
+    /// Entries in the cache database
+    pub persisted: Option<usize>,
+    pub requests: u64,
+    pub hits: u64,
+    pub hit_rate: f64,
+}
+
+/// Number of entries dropped by an invalidation
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Invalidated {
+    pub memory: usize,
+    pub persisted: usize,
+}
+
+#[derive(Clone)]
+pub struct ResultCache {
+    memory: moka::future::Cache<String, Stored>,
+    db: Option<sled::Db>,
+    ttls: Ttls,
+    requests: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl ResultCache {
+    pub fn new(ttls: Ttls, db: Option<sled::Db>) -> Self {
+        Self {
+            memory: moka::future::CacheBuilder::new(1000)
+                .initial_capacity(1000)
+                .time_to_live(ttls.max())
+                .build(),
+            db,
+            ttls,
+            requests: Arc::default(),
+            misses: Arc::default(),
+        }
+    }
+
+    /// Opens the cache configured by `CACHE_DB` and the `CACHE_*_DURATION` settings
+    pub fn open(config: &Configuration) -> Result<Self> {
+        let db = match &config.cache_db {
+            Some(path) => {
+                let db = sled::open(path)
+                    .with_context(|| format!("could not open cache database {}", path))?;
+                info!("using cache database {} with {} entries", path, db.len());
+                Some(db)
+            }
+            None => None,
+        };
+        let cache = Self::new(Ttls::from_config(config), db);
+        cache.purge_expired();
+        Ok(cache)
+    }
+
+    /// Returns the cached outcome for the URL, or runs and caches the scrape
+    ///
+    /// Concurrent calls for the same URL share a single scrape. With `refresh`, a cached
+    /// outcome is dropped and the URL scraped again.
+    pub async fn get_or_scrape<F>(&self, url: &str, refresh: bool, scrape: F) -> Outcome
+    where
+        F: Future<Output = Outcome>,
+    {
+        let key = url.to_string();
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        match self.memory.get(&key) {
+            Some(stored) if stored.is_fresh() && !refresh => {
+                RESULT_CACHE.with_label_values(&["hit"]).inc();
+                return stored.outcome;
+            }
+            Some(_) => self.memory.invalidate(&key).await,
+            None => (),
+        }
+        let mut missed = false;
+        let stored = self
+            .memory
+            .get_with(key.clone(), async {
+                if !refresh {
+                    if let Some(stored) = self.load(url) {
+                        debug!("{} found in cache database", url);
+                        return stored;
+                    }
+                }
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                missed = true;
+                let outcome = scrape.await;
+                let stored = Stored {
+                    expires: now() + self.ttls.of(&outcome).as_secs(),
+                    outcome,
+                };
+                self.store(url, &stored);
+                stored
+            })
+            .await;
+        let result = if missed { "miss" } else { "hit" };
+        RESULT_CACHE.with_label_values(&[result]).inc();
+        if !stored.is_fresh() {
+            // only kept long enough to share the scrape with concurrent requests
+            self.memory.invalidate(&key).await;
+        }
+        stored.outcome
+    }
+
+    /// Fails if the cache database does not accept writes
+    pub fn check(&self) -> Result<()> {
+        if let Some(db) = &self.db {
+            db.insert(READY_PROBE, b"")?;
+            db.remove(READY_PROBE)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the cached entry for the URL without scraping it
+    pub fn lookup(&self, url: &str) -> Option<Stored> {
+        match self.memory.get(&url.to_string()) {
+            Some(stored) if stored.is_fresh() => Some(stored),
+            _ => self.load(url),
+        }
+    }
+
+    pub async fn invalidate(&self, url: &str) -> Result<Invalidated> {
+        let key = url.to_string();
+        let memory = self.memory.get(&key).map_or(0, |_| 1);
+        self.memory.invalidate(&key).await;
+        let persisted = match &self.db {
+            Some(db) => db.remove(url)?.map_or(0, |_| 1),
+            None => 0,
+        };
+        Ok(Invalidated { memory, persisted })
+    }
+
+    /// Drops all entries whose URL matches the predicate
+    pub async fn invalidate_if<F>(&self, predicate: F) -> Result<Invalidated>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let keys: Vec<_> = self
+            .memory
+            .iter()
+            .map(|(k, _)| k)
+            .filter(|k| predicate(k))
+            .collect();
+        for key in &keys {
+            self.memory.invalidate(key.as_ref()).await;
+        }
+        let memory = keys.len();
+        let mut persisted = 0;
+        if let Some(db) = &self.db {
+            for key in db.iter().keys() {
+                let key = key?;
+                if predicate(&String::from_utf8_lossy(&key)) {
+                    db.remove(key)?;
+                    persisted += 1;
+                }
+            }
+        }
+        Ok(Invalidated { memory, persisted })
+    }
+
+    pub fn stats(&self) -> Stats {
+        let requests = self.requests.load(Ordering::Relaxed);
+        let hits = requests.saturating_sub(self.misses.load(Ordering::Relaxed));
+        Stats {
+            entries: self.memory.entry_count(),
+            persisted: self.db.as_ref().map(|x| x.len()),
+            requests,
+            hits,
+            hit_rate: if requests == 0 {
+                0.0
+            } else {
+                hits as f64 / requests as f64
+            },
+        }
+    }
+
+    fn load(&self, url: &str) -> Option<Stored> {
+        let db = self.db.as_ref()?;
+        let data = match db.get(url) {
+            Ok(data) => data?,
+            Err(e) => {
+                warn!("could not read {} from cache database: {}", url, e);
+                return None;
+            }
+        };
+        match serde_json::from_slice::<Stored>(&data) {
+            Ok(stored) if stored.is_fresh() => Some(stored),
+            Ok(_) => {
+                self.remove(url);
+                None
+            }
+            Err(e) => {
+                warn!("dropping unreadable cache entry for {}: {}", url, e);
+                self.remove(url);
+                None
+            }
+        }
+    }
+
+    fn store(&self, url: &str, stored: &Stored) {
+        let db = match &self.db {
+            Some(db) => db,
+            None => return,
+        };
+        if !stored.is_fresh() {
+            self.remove(url);
+            return;
+        }
+        let res = serde_json::to_vec(stored)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(db.insert(url, data)?));
+        if let Err(e) = res {
+            warn!("could not write {} to cache database: {}", url, e);
+        }
+    }
+
+    fn remove(&self, url: &str) {
+        if let Some(db) = &self.db {
+            if let Err(e) = db.remove(url) {
+                warn!("could not remove {} from cache database: {}", url, e);
+            }
+        }
+    }
+
+    /// Drops all expired entries from the database
+    fn purge_expired(&self) {
+        let db = match &self.db {
+            Some(db) => db,
+            None => return,
+        };
+        let expired: Vec<_> = db
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|(_, data)| {
+                serde_json::from_slice::<Stored>(data)
+                    .map(|x| !x.is_fresh())
+                    .unwrap_or(true)
+            })
+            .map(|(key, _)| key)
+            .collect();
+        if !expired.is_empty() {
+            info!("purging {} expired cache entries", expired.len());
+        }
+        for key in expired {
+            if let Err(e) = db.remove(key) {
+                warn!("could not purge cache entry: {}", e);
+            }
+        }
+    }
+}
+
+/// Key written by [`ResultCache::check`], never a valid URL
+const READY_PROBE: &[u8] = b"\0ready";
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const URL: &str = "https://example.com/image.png";
+
+    fn ttls(secs: u64) -> Ttls {
+        let ttl = Duration::from_secs(secs);
+        Ttls {
+            found: ttl,
+            empty: ttl,
+            not_found: ttl,
+            rate_limited: ttl,
+            error: ttl,
+        }
+    }
+
+    fn failed(code: ErrorKind) -> Outcome {
+        Outcome::Failed(ScrapeResultError::new(code, "failed"))
+    }
+
+    fn scrape(cache: &ResultCache, refresh: bool, outcome: Outcome) -> Outcome {
+        tokio_test::block_on(cache.get_or_scrape(URL, refresh, async { outcome }))
+    }
+
+    #[test]
+    fn test_results_survive_restart() -> Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let cache = ResultCache::new(ttls(60), Some(db.clone()));
+        assert_eq!(Outcome::Empty, scrape(&cache, false, Outcome::Empty));
+
+        let cache = ResultCache::new(ttls(60), Some(db.clone()));
+        let found = Outcome::Found(ScrapeResult::None);
+        assert_eq!(Outcome::Empty, scrape(&cache, false, found.clone()));
+        assert_eq!(found, scrape(&cache, true, found.clone()));
+
+        db.clear()?;
+        let cache = ResultCache::new(ttls(0), Some(db));
+        assert_eq!(Outcome::Empty, scrape(&cache, false, Outcome::Empty));
+        assert_eq!(found, scrape(&cache, false, found.clone()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_ttls() {
+        let ttls = Ttls {
+            rate_limited: Duration::from_secs(30),
+            ..ttls(600)
+        };
+        assert_eq!(
+            Duration::from_secs(30),
+            ttls.of(&failed(ErrorKind::UpstreamRateLimited))
+        );
+        assert_eq!(Duration::ZERO, ttls.of(&failed(ErrorKind::Timeout)));
+        let cache = ResultCache::new(ttls, None);
+        assert_eq!(
+            failed(ErrorKind::Timeout),
+            scrape(&cache, false, failed(ErrorKind::Timeout))
+        );
+        assert_eq!(Outcome::Empty, scrape(&cache, false, Outcome::Empty));
+    }
+
+    #[test]
+    fn test_invalidate_if() -> Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let cache = ResultCache::new(ttls(60), Some(db));
+        scrape(&cache, false, Outcome::Empty);
+        tokio_test::block_on(
+            cache.get_or_scrape("https://other.com/", false, async { Outcome::Empty }),
+        );
+        assert!(cache.lookup(URL).is_some());
+        let invalidated = tokio_test::block_on(
+            cache.invalidate_if(|url| url.starts_with("https://example.com/")),
+        )?;
+        assert_eq!(1, invalidated.persisted);
+        assert!(cache.lookup(URL).is_none());
+        assert!(cache.lookup("https://other.com/").is_some());
+        let stats = cache.stats();
+        assert_eq!((2, 0), (stats.requests, stats.hits));
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+//! Origin checking and CORS headers for the scrape routes
+//!
+//! `ALLOWED_ORIGINS` is a comma separated list of patterns, each optionally with a
+//! scheme and a port: `https://derpibooru.org`, `*.example.com`, `localhost:8080` or
+//! `*` for any origin. Without a scheme any scheme matches, without a port only the
+//! default port of the scheme does. An empty list allows no origin at all.
+
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    http::{self, header, HeaderValue, Request},
+    middleware::Next,
+    response::{self, IntoResponse},
+};
+use log::debug;
+
+use scraper::{ErrorKind, ScrapeResultError};
+
+use crate::apikey::ApiClient;
+use crate::web::json_response;
+use crate::{Configuration, State};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct OriginPattern {
+    scheme: Option<String>,
+    /// Lowercase host, `*` for any, or starting with `*.` for all subdomains
+    host: String,
+    port: Option<u16>,
+}
+
+impl OriginPattern {
+    fn parse(pattern: &str) -> Option<Self> {
+        let pattern = pattern.trim().to_ascii_lowercase();
+        let (scheme, rest) = match pattern.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme.to_string()), rest),
+            None => (None, &*pattern),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (host, port) = match rest.rsplit_once(':') {
+            Some((host, port)) if !rest.ends_with(']') => (host, Some(port.parse().ok()?)),
+            _ => (rest, None),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        Some(Self {
+            scheme,
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    fn matches(&self, origin: &url::Url) -> bool {
+        let host = match origin.host_str() {
+            Some(host) => host.to_ascii_lowercase(),
+            None => return false,
+        };
+        let host_matches = if self.host == "*" {
+            true
+        } else if let Some(parent) = self.host.strip_prefix("*.") {
+            host.ends_with(&format!(".{}", parent))
+        } else {
+            host == self.host
+        };
+        let port_matches = match self.port {
+            Some(port) => origin.port_or_known_default() == Some(port),
+            None => origin.port().is_none() || self.host == "*",
+        };
+        host_matches && port_matches && self.scheme.iter().all(|x| x == origin.scheme())
+    }
+}
+
+/// Which origins may call the scraper and what they may send
+#[derive(Clone)]
+pub struct Policy {
+    origins: Vec<OriginPattern>,
+    allow_empty_origin: bool,
+    methods: HeaderValue,
+    headers: HeaderValue,
+    max_age: HeaderValue,
+}
+
+impl Policy {
+    pub fn new(config: &Configuration) -> anyhow::Result<Self> {
+        let origins = config
+            .allowed_origins
+            .split(',')
+            .filter(|x| !x.trim().is_empty())
+            .map(|x| {
+                OriginPattern::parse(x)
+                    .ok_or_else(|| anyhow::anyhow!("invalid pattern in ALLOWED_ORIGINS: {}", x))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            origins,
+            allow_empty_origin: config.allow_empty_origin,
+            methods: HeaderValue::from_str(&config.cors_allowed_methods)?,
+            headers: HeaderValue::from_str(&config.cors_allowed_headers)?,
+            max_age: HeaderValue::from(config.cors_max_age),
+        })
+    }
+
+    pub fn is_allowed_origin(&self, origin: Option<&str>) -> bool {
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return self.allow_empty_origin,
+        };
+        match url::Url::parse(origin) {
+            Ok(origin) => self.origins.iter().any(|x| x.matches(&origin)),
+            Err(_) => false,
+        }
+    }
+
+    fn is_allowed_method(&self, method: &str) -> bool {
+        self.methods
+            .to_str()
+            .unwrap_or_default()
+            .split(',')
+            .any(|x| x.trim().eq_ignore_ascii_case(method))
+    }
+
+    /// Headers telling the browser that the origin may read the response
+    fn apply(&self, origin: HeaderValue, headers: &mut http::HeaderMap) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.append(header::VARY, HeaderValue::from_static("origin"));
+        headers.insert(
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            HeaderValue::from_static("x-time-taken"),
+        );
+    }
+
+    /// Response to a preflight request of an allowed origin
+    fn preflight(&self, origin: HeaderValue, method: &str) -> response::Response {
+        if !self.is_allowed_method(method) {
+            return forbidden(&format!("method {} is not allowed", method));
+        }
+        let mut res = http::StatusCode::NO_CONTENT.into_response();
+        let headers = res.headers_mut();
+        self.apply(origin, headers);
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, self.methods.clone());
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, self.headers.clone());
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, self.max_age.clone());
+        res
+    }
+}
+
+fn forbidden(message: &str) -> response::Response {
+    json_response(
+        http::StatusCode::FORBIDDEN,
+        &ScrapeResultError::new(ErrorKind::Forbidden, message),
+    )
+    .into_response()
+}
+
+pub async fn check(req: Request<Body>, state: Arc<State>, next: Next<Body>) -> response::Response {
+    let origin = req.headers().get(header::ORIGIN).cloned();
+    let origin_str = match origin.as_ref().map(|x| x.to_str()).transpose() {
+        Ok(origin) => origin,
+        Err(_) => return http::StatusCode::BAD_REQUEST.into_response(),
+    };
+    let policy = &state.cors;
+    let keyed = req.extensions().get::<ApiClient>().is_some();
+    if keyed && state.config.api_keys_bypass_origin {
+        return next.run(req).await;
+    }
+    if !policy.is_allowed_origin(origin_str) {
+        debug!("refusing request from origin {:?}", origin_str);
+        return forbidden("origin is not allowed");
+    }
+    let origin = match origin {
+        Some(origin) => origin,
+        None => return next.run(req).await,
+    };
+    let preflight = req
+        .headers()
+        .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+        .and_then(|x| x.to_str().ok());
+    if req.method() == http::Method::OPTIONS {
+        if let Some(method) = preflight {
+            return policy.preflight(origin, method);
+        }
+    }
+    let mut res = next.run(req).await;
+    policy.apply(origin, res.headers_mut());
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy(origins: &str) -> Policy {
+        Policy::new(&Configuration {
+            allowed_origins: origins.to_string(),
+            ..Configuration::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_origin_patterns() {
+        let patterns = policy("https://derpibooru.org,*.example.com,localhost:8080");
+        assert!(patterns.is_allowed_origin(Some("https://derpibooru.org")));
+        assert!(!patterns.is_allowed_origin(Some("http://derpibooru.org")));
+        assert!(!patterns.is_allowed_origin(Some("https://derpibooru.org:8443")));
+        assert!(!patterns.is_allowed_origin(Some("https://evilderpibooru.org")));
+        assert!(patterns.is_allowed_origin(Some("https://a.example.com")));
+        assert!(patterns.is_allowed_origin(Some("http://a.b.example.com")));
+        assert!(!patterns.is_allowed_origin(Some("https://example.com")));
+        assert!(!patterns.is_allowed_origin(Some("https://notexample.com")));
+        assert!(patterns.is_allowed_origin(Some("http://localhost:8080")));
+        assert!(!patterns.is_allowed_origin(Some("http://localhost")));
+        assert!(!patterns.is_allowed_origin(Some("null")));
+        assert!(!patterns.is_allowed_origin(None));
+        assert!(policy("*").is_allowed_origin(Some("http://localhost:3000")));
+        assert!(Policy::new(&Configuration {
+            allowed_origins: "localhost:http".to_string(),
+            ..Configuration::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_empty_origins_deny_all() {
+        let empty = policy("");
+        assert!(!empty.is_allowed_origin(Some("http://localhost:3000")));
+        assert!(!empty.is_allowed_origin(Some("https://derpibooru.org")));
+        assert!(!policy(" , ").is_allowed_origin(Some("http://localhost")));
+    }
+
+    #[test]
+    fn test_preflight() {
+        let policy = policy("https://derpibooru.org");
+        let origin = HeaderValue::from_static("https://derpibooru.org");
+        let res = policy.preflight(origin.clone(), "POST");
+        assert_eq!(http::StatusCode::NO_CONTENT, res.status());
+        assert_eq!(
+            "https://derpibooru.org",
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+        assert_eq!(
+            "GET,POST",
+            res.headers()[header::ACCESS_CONTROL_ALLOW_METHODS]
+        );
+        let res = policy.preflight(origin, "DELETE");
+        assert_eq!(http::StatusCode::FORBIDDEN, res.status());
+    }
+}
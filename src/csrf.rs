@@ -0,0 +1,371 @@
+//! CSRF token checking for the scrape routes, enabled via `CHECK_CSRF_PRESENCE`
+//!
+//! Scrapes via `GET /images/scrape` are checked as well, as they make the server fetch
+//! URLs just like POST requests. The token is taken from the `x-csrf-token` header,
+//! the `_csrf_token` field of a JSON or form body or, for GET requests, the
+//! `_csrf_token` query parameter. Without a `CSRF_SECRET` only its presence is checked, with one it
+//! has to be a `Phoenix.Token` signed with that secret (Philomena's `secret_key_base`)
+//! and the `CSRF_SALT`, ie. created via `Phoenix.Token.sign(endpoint, salt, data)`.
+
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    body::{Body, HttpBody},
+    http::{self, Request},
+    middleware::Next,
+    response::{self, IntoResponse},
+};
+use hmac::{Hmac, Mac, NewMac};
+use log::debug;
+use sha2::Sha256;
+
+use scraper::{ErrorKind, ScrapeResultError};
+
+use crate::apikey::ApiClient;
+use crate::web::json_response;
+use crate::State;
+
+const HEADER: &str = "x-csrf-token";
+const FIELD: &str = "_csrf_token";
+/// Bodies larger than this are refused before looking for a token
+const MAX_BODY: usize = 64 * 1024;
+/// Iterations of `Plug.Crypto.KeyGenerator`
+const KEY_ITERATIONS: u32 = 1000;
+
+/// Verifies tokens signed by `Phoenix.Token`
+#[derive(Clone)]
+pub struct Verifier {
+    key: [u8; 32],
+    max_age: Duration,
+}
+
+impl Verifier {
+    pub fn new(secret_key_base: &str, salt: &str, max_age: Duration) -> Self {
+        let mut key = [0; 32];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(
+            secret_key_base.as_bytes(),
+            salt.as_bytes(),
+            KEY_ITERATIONS,
+            &mut key,
+        );
+        Self { key, max_age }
+    }
+
+    /// Checks signature and age of the token, `now` is in milliseconds since the epoch
+    fn verify(&self, token: &str, now: u64) -> Result<(), &'static str> {
+        let mut parts = token.split('.');
+        let (protected, payload, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(protected), Some(payload), Some(signature)) if parts.next().is_none() => {
+                (protected, payload, signature)
+            }
+            _ => return Err("CSRF token is malformed"),
+        };
+        // base64 of "HS256", the only algorithm Phoenix.Token signs with
+        if protected != "SFMyNTY" {
+            return Err("CSRF token is malformed");
+        }
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| "CSRF token is malformed")?;
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC accepts any key size");
+        mac.update(protected.as_bytes());
+        mac.update(b".");
+        mac.update(payload.as_bytes());
+        mac.verify(&signature)
+            .map_err(|_| "CSRF token is invalid")?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| "CSRF token is malformed")?;
+        let signed_at = signed_at(&payload).ok_or("CSRF token is malformed")?;
+        if signed_at.saturating_add(self.max_age.as_millis() as u64) < now {
+            return Err("CSRF token has expired");
+        }
+        Ok(())
+    }
+}
+
+/// Extracts `signed_at` from the `{data, signed_at, max_age}` tuple, encoded in the
+/// Erlang external term format
+fn signed_at(payload: &[u8]) -> Option<u64> {
+    let mut term = Term(payload);
+    if term.byte()? != 131 || term.byte()? != 104 || term.byte()? != 3 {
+        return None;
+    }
+    term.skip()?;
+    term.integer()
+}
+
+/// Reader for the subset of the external term format `term_to_binary` emits for
+/// plain data
+struct Term<'a>(&'a [u8]);
+
+impl Term<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|x| x[0])
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().ok()?).into())
+    }
+
+    fn u32(&mut self) -> Option<usize> {
+        u32::from_be_bytes(self.take(4)?.try_into().ok()?)
+            .try_into()
+            .ok()
+    }
+
+    fn integer(&mut self) -> Option<u64> {
+        match self.byte()? {
+            97 => self.byte().map(u64::from),
+            98 => i32::from_be_bytes(self.take(4)?.try_into().ok()?)
+                .try_into()
+                .ok(),
+            110 => {
+                let len = self.byte()?.into();
+                if self.byte()? != 0 || len > 8 {
+                    return None;
+                }
+                let digits = self.take(len)?;
+                Some(
+                    digits
+                        .iter()
+                        .rev()
+                        .fold(0, |acc, x| (acc << 8) | u64::from(*x)),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    fn skip(&mut self) -> Option<()> {
+        match self.byte()? {
+            // small integer, integer, float
+            97 => self.take(1).map(drop),
+            98 => self.take(4).map(drop),
+            70 => self.take(8).map(drop),
+            // atoms
+            100 | 118 => {
+                let len = self.u16()?;
+                self.take(len).map(drop)
+            }
+            115 | 119 => {
+                let len = self.byte()?.into();
+                self.take(len).map(drop)
+            }
+            // big integers
+            110 => {
+                let len = usize::from(self.byte()?) + 1;
+                self.take(len).map(drop)
+            }
+            111 => {
+                let len = self.u32()? + 1;
+                self.take(len).map(drop)
+            }
+            // nil, string, binary
+            106 => Some(()),
+            107 => {
+                let len = self.u16()?;
+                self.take(len).map(drop)
+            }
+            109 => {
+                let len = self.u32()?;
+                self.take(len).map(drop)
+            }
+            // tuples, list with tail, map
+            104 => {
+                let arity = self.byte()?;
+                (0..arity).try_for_each(|_| self.skip())
+            }
+            105 => {
+                let arity = self.u32()?;
+                (0..arity).try_for_each(|_| self.skip())
+            }
+            108 => {
+                let len = self.u32()?;
+                (0..=len).try_for_each(|_| self.skip())
+            }
+            116 => {
+                let len = self.u32()?;
+                (0..len * 2).try_for_each(|_| self.skip())
+            }
+            _ => None,
+        }
+    }
+}
+
+fn forbidden(message: &str) -> response::Response<String> {
+    json_response(
+        http::StatusCode::FORBIDDEN,
+        &ScrapeResultError::new(ErrorKind::Forbidden, message),
+    )
+}
+
+/// Token from the form or JSON body, depending on the content type
+fn token_from_body(headers: &http::HeaderMap, body: &[u8]) -> Option<String> {
+    let content_type = headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default();
+    if content_type.starts_with("application/json") {
+        let body: serde_json::Value = serde_json::from_slice(body).ok()?;
+        body.get(FIELD)?.as_str().map(|x| x.to_string())
+    } else if content_type.starts_with("application/x-www-form-urlencoded") {
+        url::form_urlencoded::parse(body)
+            .find(|(key, _)| key == FIELD)
+            .map(|(_, value)| value.into_owned())
+    } else {
+        None
+    }
+}
+
+fn token_from_query(uri: &http::Uri) -> Option<String> {
+    url::form_urlencoded::parse(uri.query()?.as_bytes())
+        .find(|(key, _)| key == FIELD)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Requests which only read, all but the GET scrape
+fn is_safe(req: &Request<Body>) -> bool {
+    match *req.method() {
+        http::Method::GET | http::Method::HEAD => req.uri().path() != "/images/scrape",
+        http::Method::OPTIONS => true,
+        _ => false,
+    }
+}
+
+pub async fn check(req: Request<Body>, state: Arc<State>, next: Next<Body>) -> response::Response {
+    // browsers do not send the Authorization header on their own, so keyed requests
+    // can not be forged
+    if !state.config.check_csrf_presence
+        || req.extensions().get::<ApiClient>().is_some()
+        || is_safe(&req)
+    {
+        return next.run(req).await;
+    }
+    let (parts, mut body) = req.into_parts();
+    let header = parts
+        .headers
+        .get(HEADER)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_string())
+        .or_else(|| token_from_query(&parts.uri));
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) if bytes.len() + chunk.len() <= MAX_BODY => bytes.extend_from_slice(&chunk),
+            Ok(_) => return http::StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+            Err(_) => return http::StatusCode::BAD_REQUEST.into_response(),
+        }
+    }
+    let token = header.or_else(|| token_from_body(&parts.headers, &bytes));
+    let token = match token {
+        Some(token) if !token.is_empty() => token,
+        _ => return forbidden("CSRF token is missing").into_response(),
+    };
+    if let Some(verifier) = &state.csrf {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        if let Err(e) = verifier.verify(&token, now) {
+            debug!("refusing request: {}", e);
+            return forbidden(e).into_response();
+        }
+    }
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// `Phoenix.Token.sign(endpoint, "scraper csrf", "session")` with a secret_key_base
+    /// of 64 "x" at 1700000000000ms
+    const TOKEN: &str = "SFMyNTY.g2gDbQAAAAdzZXNzaW9ubgYAAGjlz4sBYgABUYA.KHl4GEj_Qy6PsEgdPZaNLFmqK7XVkPZqS98DilqVpyU";
+    const SIGNED_AT: u64 = 1_700_000_000_000;
+
+    #[test]
+    fn test_verify_phoenix_token() {
+        let secret = "x".repeat(64);
+        let max_age = Duration::from_secs(86400);
+        let verifier = Verifier::new(&secret, "scraper csrf", max_age);
+        assert_eq!(Ok(()), verifier.verify(TOKEN, SIGNED_AT + 1000));
+        assert_eq!(
+            Err("CSRF token has expired"),
+            verifier.verify(TOKEN, SIGNED_AT + 86_400_001)
+        );
+        let tampered = TOKEN.replace("KHl4", "KHl5");
+        assert_eq!(
+            Err("CSRF token is invalid"),
+            verifier.verify(&tampered, SIGNED_AT)
+        );
+        assert_eq!(
+            Err("CSRF token is malformed"),
+            verifier.verify("not a token", SIGNED_AT)
+        );
+        let other_salt = Verifier::new(&secret, "user socket", max_age);
+        assert_eq!(
+            Err("CSRF token is invalid"),
+            other_salt.verify(TOKEN, SIGNED_AT)
+        );
+    }
+
+    #[test]
+    fn test_token_from_body() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(None, token_from_body(&headers, b"_csrf_token=abc"));
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        assert_eq!(
+            Some("a+c".to_string()),
+            token_from_body(&headers, b"url=x&_csrf_token=a%2Bc")
+        );
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/json; charset=utf-8"),
+        );
+        assert_eq!(
+            Some("abc".to_string()),
+            token_from_body(&headers, br#"{"url":"x","_csrf_token":"abc"}"#)
+        );
+        assert_eq!(None, token_from_body(&headers, br#"{"url":"x"}"#));
+    }
+
+    #[test]
+    fn test_get_scrapes_are_checked() {
+        let req = |method, uri| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert!(!is_safe(&req(
+            http::Method::GET,
+            "/images/scrape?url=https://example.com/a.png"
+        )));
+        assert!(!is_safe(&req(http::Method::POST, "/jobs")));
+        assert!(is_safe(&req(http::Method::GET, "/jobs/1")));
+        assert!(is_safe(&req(http::Method::OPTIONS, "/images/scrape")));
+        assert_eq!(
+            Some("a+c".to_string()),
+            token_from_query(&"/images/scrape?url=x&_csrf_token=a%2Bc".parse().unwrap())
+        );
+        assert_eq!(
+            None,
+            token_from_query(&"/images/scrape?url=x".parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use serde::{Deserialize, Serialize};
+
+/// Machine readable classification of a failed scrape
+#[derive(
+    Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default, utoipa::ToSchema,
+)]
+#[cfg_attr(test, derive(visit_diff::Diff))]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// No scraper accepts the URL
+    UnsupportedUrl,
+    /// The URL could not be parsed
+    InvalidUrl,
+    /// The post or image does not exist (anymore)
+    UpstreamNotFound,
+    /// Upstream answered that we sent too many requests
+    UpstreamRateLimited,
+    /// Our own budget of requests to the upstream host is exhausted
+    RateLimited,
+    /// Upstream refused the request, ie. because an API key is missing or invalid
+    AuthRequired,
+    /// Upstream failed or answered with an unexpected status
+    UpstreamError,
+    /// Upstream answered but the response did not have the expected shape
+    ParseFailed,
+    /// Upstream did not answer in time
+    Timeout,
+    /// The request to the scraper itself was refused, ie. because of a missing CSRF token
+    Forbidden,
+    /// The API key sent to the scraper itself is malformed or unknown
+    Unauthorized,
+    /// A resource of the scraper itself, ie. a job, does not exist
+    NotFound,
+    #[default]
+    Internal,
+}
+
+impl ErrorKind {
+    pub const ALL: [Self; 13] = [
+        Self::UnsupportedUrl,
+        Self::InvalidUrl,
+        Self::UpstreamNotFound,
+        Self::UpstreamRateLimited,
+        Self::RateLimited,
+        Self::AuthRequired,
+        Self::UpstreamError,
+        Self::ParseFailed,
+        Self::Timeout,
+        Self::Forbidden,
+        Self::Unauthorized,
+        Self::NotFound,
+        Self::Internal,
+    ];
+
+    /// Name of the kind as serialized, ie. for metric labels
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::UnsupportedUrl => "unsupported_url",
+            Self::InvalidUrl => "invalid_url",
+            Self::UpstreamNotFound => "upstream_not_found",
+            Self::UpstreamRateLimited => "upstream_rate_limited",
+            Self::RateLimited => "rate_limited",
+            Self::AuthRequired => "auth_required",
+            Self::UpstreamError => "upstream_error",
+            Self::ParseFailed => "parse_failed",
+            Self::Timeout => "timeout",
+            Self::Forbidden => "forbidden",
+            Self::Unauthorized => "unauthorized",
+            Self::NotFound => "not_found",
+            Self::Internal => "internal",
+        }
+    }
+
+    pub fn from_status(status: reqwest::StatusCode) -> Self {
+        use reqwest::StatusCode;
+        match status {
+            StatusCode::NOT_FOUND | StatusCode::GONE => Self::UpstreamNotFound,
+            StatusCode::TOO_MANY_REQUESTS => Self::UpstreamRateLimited,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::AuthRequired,
+            StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => Self::Timeout,
+            _ => Self::UpstreamError,
+        }
+    }
+
+    /// Finds the most fitting kind for an error chain
+    ///
+    /// An explicit [`ScrapeError`] takes precedence, otherwise the kind is guessed
+    /// from known error types in the chain.
+    pub fn from_error(e: &anyhow::Error) -> Self {
+        if let Some(e) = e.chain().find_map(|e| e.downcast_ref::<ScrapeError>()) {
+            return e.kind;
+        }
+        for e in e.chain() {
+            if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+                return if e.is_timeout() {
+                    Self::Timeout
+                } else if let Some(status) = e.status() {
+                    Self::from_status(status)
+                } else if e.is_decode() {
+                    Self::ParseFailed
+                } else {
+                    Self::UpstreamError
+                };
+            }
+            if e.is::<serde_json::Error>() {
+                return Self::ParseFailed;
+            }
+            if e.is::<url::ParseError>() {
+                return Self::InvalidUrl;
+            }
+        }
+        Self::Internal
+    }
+}
+
+/// Error with a known [`ErrorKind`], raised by scrapers via `anyhow::bail!`
+#[derive(Debug, Clone)]
+pub struct ScrapeError {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl ScrapeError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn parse_failed(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::ParseFailed, message)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl std::fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ScrapeError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_error_kind_from_chain() {
+        let e: anyhow::Result<()> = Err(ScrapeError::parse_failed("no image found").into());
+        let e = e.context("could not extract page data").unwrap_err();
+        assert_eq!(ErrorKind::ParseFailed, ErrorKind::from_error(&e));
+
+        let e = serde_json::from_str::<serde_json::Value>("{")
+            .context("could not parse response")
+            .unwrap_err();
+        assert_eq!(ErrorKind::ParseFailed, ErrorKind::from_error(&e));
+
+        let e = url::Url::parse("not a url")
+            .context("could not parse URL for scraper")
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidUrl, ErrorKind::from_error(&e));
+
+        let e = anyhow::format_err!("something else");
+        assert_eq!(ErrorKind::Internal, ErrorKind::from_error(&e));
+    }
+
+    #[test]
+    fn test_error_kind_serialization() -> anyhow::Result<()> {
+        assert_eq!(
+            r#""upstream_rate_limited""#,
+            serde_json::to_string(&ErrorKind::UpstreamRateLimited)?
+        );
+        assert_eq!(
+            ErrorKind::UpstreamNotFound,
+            ErrorKind::from_status(reqwest::StatusCode::NOT_FOUND)
+        );
+        for kind in ErrorKind::ALL {
+            assert_eq!(
+                format!(r#""{}""#, kind.as_str()),
+                serde_json::to_string(&kind)?
+            );
+        }
+        Ok(())
+    }
+}
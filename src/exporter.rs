@@ -0,0 +1,90 @@
+//! Prometheus metrics of the service, served at `/metrics` together with those of the
+//! scraper library
+
+use axum::{
+    extract::MatchedPath,
+    http::{self, Request},
+    middleware::Next,
+    response,
+};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec,
+    TextEncoder,
+};
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "scraper_http_requests_total",
+        "Requests handled by route and status",
+        &["route", "status"]
+    )
+    .expect("failure in setting up metrics");
+    static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "scraper_http_request_duration_seconds",
+        "Time taken to handle requests by route",
+        &["route"],
+        vec![0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]
+    )
+    .expect("failure in setting up metrics");
+    /// Lookups in the result cache, `hit` or `miss`
+    pub static ref RESULT_CACHE: IntCounterVec = register_int_counter_vec!(
+        "scraper_result_cache_requests_total",
+        "Lookups in the result cache by result",
+        &["result"]
+    )
+    .expect("failure in setting up metrics");
+}
+
+/// Counts and times requests by their route, so unknown paths can't blow up the labels
+pub async fn track<B>(req: Request<B>, next: Next<B>) -> response::Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|x| x.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let timer = HTTP_DURATION.with_label_values(&[&route]).start_timer();
+    let res = next.run(req).await;
+    timer.observe_duration();
+    HTTP_REQUESTS
+        .with_label_values(&[&route, res.status().as_str()])
+        .inc();
+    res
+}
+
+pub async fn render() -> response::Response<String> {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    let (status, body) = match encoder.encode(&prometheus::gather(), &mut body) {
+        Ok(()) => (
+            http::StatusCode::OK,
+            String::from_utf8(body).unwrap_or_default(),
+        ),
+        Err(e) => {
+            log::error!("could not encode metrics: {}", e);
+            (http::StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        }
+    };
+    let mut res = response::Response::new(body);
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static(prometheus::TEXT_FORMAT),
+    );
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        RESULT_CACHE.with_label_values(&["hit"]).inc();
+        let res = tokio_test::block_on(render());
+        assert_eq!(http::StatusCode::OK, res.status());
+        assert!(res
+            .body()
+            .contains(r#"scraper_result_cache_requests_total{result="hit"}"#));
+    }
+}
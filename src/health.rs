@@ -0,0 +1,279 @@
+//! Liveness, readiness and per scraper status for load balancers and on-call
+//!
+//! Every `CANARY_INTERVAL` the URLs in `CANARY_URLS` are scraped, bypassing the result
+//! and response caches, and `/status` reports when each scraper last succeeded and why
+//! it last failed.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::{http, response, routing::get, Extension, Router};
+use log::{info, warn};
+use serde::Serialize;
+
+use scraper::{ScrapeResult, ScrapeResultError};
+
+use crate::cache::now;
+use crate::web::json_response;
+use crate::{Configuration, State};
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/status", get(status))
+}
+
+/// Last canary scrape of a scraper, times are seconds since the epoch
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CanaryStatus {
+    pub url: String,
+    pub last_run: Option<u64>,
+    pub last_success: Option<u64>,
+    pub last_error: Option<ScrapeResultError>,
+}
+
+/// Canary URL and last result per scraper name
+#[derive(Clone, Default)]
+pub struct Canaries {
+    status: Arc<Mutex<HashMap<String, CanaryStatus>>>,
+}
+
+impl Canaries {
+    pub fn new(config: &Configuration) -> Result<Self> {
+        let mut status = HashMap::new();
+        for entry in config
+            .canary_urls
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|x| !x.trim().is_empty())
+        {
+            let (name, url) = match entry.split_once('=') {
+                Some((name, url)) => (name.trim(), url.trim()),
+                None => anyhow::bail!("expected Scraper=URL in CANARY_URLS, got {}", entry),
+            };
+            if !scraper::REGISTRY.names().any(|x| x == name) {
+                warn!("canary for unknown scraper {}", name);
+            }
+            status.insert(
+                name.to_string(),
+                CanaryStatus {
+                    url: url.to_string(),
+                    ..CanaryStatus::default()
+                },
+            );
+        }
+        Ok(Self {
+            status: Arc::new(Mutex::new(status)),
+        })
+    }
+
+    fn get(&self, name: &str) -> Option<CanaryStatus> {
+        self.status.lock().unwrap().get(name).cloned()
+    }
+
+    fn urls(&self) -> Vec<(String, String)> {
+        let status = self.status.lock().unwrap();
+        status
+            .iter()
+            .map(|(name, x)| (name.clone(), x.url.clone()))
+            .collect()
+    }
+
+    fn record(&self, name: &str, error: Option<ScrapeResultError>) {
+        let mut status = self.status.lock().unwrap();
+        if let Some(status) = status.get_mut(name) {
+            let now = now();
+            status.last_run = Some(now);
+            match error {
+                Some(error) => status.last_error = Some(error),
+                None => status.last_success = Some(now),
+            }
+        }
+    }
+}
+
+/// Scrapes one canary URL, it counts as success only if the URL was dispatched to the
+/// scraper it is configured for and images were found
+async fn run_canary(state: &State, name: &str, url: &str) {
+    let config = &state.config.scraper;
+    // canaries have to reach upstream, a cached response would hide its breakage
+    let clients = state.clients.refreshing();
+    let res = match scraper::REGISTRY.scraper_name(config, &clients, url).await {
+        Ok(Some(dispatched)) if dispatched == name => {
+            scraper::REGISTRY.scrape(config, &clients, url).await
+        }
+        Ok(dispatched) => Ok(Some(ScrapeResult::Err(ScrapeResultError::new(
+            scraper::ErrorKind::UnsupportedUrl,
+            format!(
+                "canary URL is scraped by {} instead",
+                dispatched.unwrap_or("no scraper")
+            ),
+        )))),
+        Err(e) => Err(e),
+    };
+    let error = match res {
+        Ok(Some(ScrapeResult::Ok(data))) if !data.images.is_empty() => None,
+        Ok(Some(ScrapeResult::Err(e))) => Some(e),
+        Ok(_) => Some(ScrapeResultError::new(
+            scraper::ErrorKind::ParseFailed,
+            "canary scrape found no images",
+        )),
+        Err(e) => Some(ScrapeResultError::from_error(&e)),
+    };
+    match &error {
+        Some(e) => warn!("canary of {} failed: {:?}", name, e.errors),
+        None => info!("canary of {} succeeded", name),
+    }
+    state.canaries.record(name, error);
+}
+
+/// Runs the canaries periodically for the lifetime of the process
+pub fn spawn_canaries(state: Arc<State>) {
+    if state.config.canary_interval == 0 || state.canaries.urls().is_empty() {
+        return;
+    }
+    let interval = Duration::from_secs(state.config.canary_interval);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            for (name, url) in state.canaries.urls() {
+                run_canary(&state, &name, &url).await;
+            }
+        }
+    });
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    errors: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ScraperStatus {
+    name: &'static str,
+    enabled: bool,
+    canary: Option<CanaryStatus>,
+}
+
+pub async fn healthz() -> response::Response<String> {
+    json_response(http::StatusCode::OK, &serde_json::json!({ "status": "ok" }))
+}
+
+/// Problems that keep the service from answering scrape requests
+fn readiness_errors(state: &State) -> Vec<String> {
+    let mut errors = Vec::new();
+    let enabled = scraper::REGISTRY
+        .names()
+        .filter(|x| state.config.scraper.is_enabled_scraper(x))
+        .count();
+    if enabled == 0 {
+        errors.push("no scraper is enabled".to_string());
+    }
+    if let Err(e) = state.result_cache.check() {
+        errors.push(format!("result cache is not usable: {:#}", e));
+    }
+    errors
+}
+
+pub async fn readyz(Extension(state): Extension<Arc<State>>) -> response::Response<String> {
+    let errors = readiness_errors(&state);
+    let status = if errors.is_empty() {
+        http::StatusCode::OK
+    } else {
+        http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    json_response(
+        status,
+        &Readiness {
+            ready: errors.is_empty(),
+            errors,
+        },
+    )
+}
+
+pub async fn status(Extension(state): Extension<Arc<State>>) -> response::Response<String> {
+    let scrapers: Vec<_> = scraper::REGISTRY
+        .names()
+        .map(|name| ScraperStatus {
+            name,
+            enabled: state.config.scraper.is_enabled_scraper(name),
+            canary: state.canaries.get(name),
+        })
+        .collect();
+    json_response(
+        http::StatusCode::OK,
+        &serde_json::json!({ "scrapers": scrapers }),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_canary_status() -> Result<()> {
+        let config = Configuration {
+            canary_urls: Some(
+                "Twitter=https://twitter.com/a/status/1, Raw=https://example.com/a.png".to_string(),
+            ),
+            ..Configuration::default()
+        };
+        let canaries = Canaries::new(&config)?;
+        assert_eq!(2, canaries.urls().len());
+        assert_eq!(None, canaries.get("Twitter").and_then(|x| x.last_run));
+        canaries.record("Twitter", None);
+        let error = ScrapeResultError::new(scraper::ErrorKind::Timeout, "too slow");
+        canaries.record("Raw", Some(error.clone()));
+        let twitter = canaries.get("Twitter").unwrap();
+        assert!(twitter.last_success.is_some());
+        assert_eq!(twitter.last_run, twitter.last_success);
+        let raw = canaries.get("Raw").unwrap();
+        assert_eq!(None, raw.last_success);
+        assert_eq!(Some(error), raw.last_error);
+        let config = Configuration {
+            canary_urls: Some("https://example.com/a.png".to_string()),
+            ..Configuration::default()
+        };
+        assert!(Canaries::new(&config).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_canary_of_other_scraper_fails() -> Result<()> {
+        let state = State::new(Configuration {
+            canary_urls: Some("Raw=https://twitter.com/a/status/1".to_string()),
+            ..Configuration::default()
+        })?;
+        run_canary(&state, "Raw", "https://twitter.com/a/status/1").await;
+        let raw = state.canaries.get("Raw").unwrap();
+        assert_eq!(None, raw.last_success);
+        let error = raw.last_error.unwrap();
+        assert_eq!(scraper::ErrorKind::UnsupportedUrl, error.code);
+        assert!(error.errors[0].ends_with("instead"), "{:?}", error.errors);
+        Ok(())
+    }
+
+    #[test]
+    fn test_readiness() -> Result<()> {
+        let state = State::new(Configuration::default())?;
+        assert!(readiness_errors(&state).is_empty());
+        let scraper = scraper::REGISTRY
+            .names()
+            .fold(scraper::Configuration::builder(), |x, name| {
+                x.disable_scraper(name)
+            })
+            .build();
+        let state = State::new(Configuration {
+            scraper,
+            ..Configuration::default()
+        })?;
+        assert_eq!(vec!["no scraper is enabled"], readiness_errors(&state));
+        Ok(())
+    }
+}
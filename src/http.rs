@@ -0,0 +1,666 @@
+//! Wrapper around [`reqwest::Client`] used for all requests made by scrapers
+//!
+//! Clients are shared through a [`ClientPool`], keyed by their [`ClientSettings`], so
+//! connections are kept alive between scrapes. Requests of all clients in a pool share
+//! a per host [`RateLimit`]. Idempotent requests failing with a transient error are
+//! repeated according to the client's [`RetryPolicy`]. Their responses are cached for
+//! `CACHE_HTTP_DURATION`, unless upstream forbids it. Clients of a
+//! [`ClientPool::refreshing`] pool don't answer from that cache but still update it.
+//!
+//! Requests and host lookups can be answered from a recorded [`Cassette`] instead of
+//! the network, which is used by the tests to run without internet access.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use log::{debug, trace, warn};
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::{IntoUrl, Method};
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::{host_label, RESPONSE_CACHE, UPSTREAM_REQUESTS, UPSTREAM_RETRIES};
+use crate::Configuration;
+
+mod cache;
+mod limit;
+mod retry;
+
+use cache::ResponseCache;
+pub use limit::RateLimit;
+use limit::{HostLimiter, Limiters};
+pub use retry::RetryPolicy;
+
+lazy_static::lazy_static! {
+    static ref CASSETTES: Mutex<HashMap<PathBuf, Arc<Cassette>>> = Mutex::new(HashMap::new());
+}
+
+/// Query parameters which are not written to or matched against cassettes
+const REDACTED_PARAMS: &[&str] = &["api_key"];
+
+/// How a client handles redirects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Redirect {
+    None,
+    Limited(usize),
+}
+
+/// Settings a scraper needs from its client, clients with equal settings are shared
+#[derive(Clone, PartialEq, Eq, Hash, securefmt::Debug)]
+pub struct ClientSettings {
+    #[sensitive]
+    proxy_url: Option<String>,
+    redirect: Redirect,
+    cookies: bool,
+    replay: Option<String>,
+    record: Option<String>,
+    rate_limit: RateLimit,
+    retry: RetryPolicy,
+    cache_ttl: std::time::Duration,
+}
+
+impl ClientSettings {
+    /// Default settings for the given configuration, redirects are not followed
+    pub fn new(config: &Configuration) -> Self {
+        Self {
+            proxy_url: config.proxy_url.clone(),
+            redirect: Redirect::None,
+            cookies: true,
+            replay: config.http_replay.clone(),
+            record: config.http_record.clone(),
+            rate_limit: config.rate_limit(),
+            retry: config.retry_policy(),
+            cache_ttl: std::time::Duration::from_secs(config.http_cache_duration),
+        }
+    }
+
+    pub fn redirect(self, redirect: Redirect) -> Self {
+        Self { redirect, ..self }
+    }
+
+    /// Keep a cookie jar shared by all requests of this client
+    pub fn cookies(self, cookies: bool) -> Self {
+        Self { cookies, ..self }
+    }
+
+    pub fn proxy_url(self, proxy_url: Option<String>) -> Self {
+        Self { proxy_url, ..self }
+    }
+
+    fn build(&self, limiters: Limiters, responses: ResponseCache) -> Result<Client> {
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_millis(5000))
+            .connect_timeout(std::time::Duration::from_millis(2500))
+            .user_agent("curl/7.83.1")
+            .cookie_store(self.cookies)
+            .redirect(match self.redirect {
+                Redirect::None => reqwest::redirect::Policy::none(),
+                Redirect::Limited(max) => reqwest::redirect::Policy::limited(max),
+            });
+        let client = match self.proxy_url.clone() {
+            None => client,
+            Some(proxy_url) => {
+                use reqwest::Proxy;
+                use std::str::FromStr;
+                let proxy_url = url::Url::from_str(&proxy_url)?;
+                let proxy = match proxy_url.scheme() {
+                    "http" => Proxy::all(proxy_url)?,
+                    "https" => Proxy::all(proxy_url)?,
+                    "socks" => Proxy::all(proxy_url)?,
+                    "socks5" => Proxy::all(proxy_url)?,
+                    _ => anyhow::bail!(
+                        "unknown client proxy protocol, specify http, https, socks or socks5"
+                    ),
+                };
+                client.proxy(proxy)
+            }
+        };
+        Ok(Client {
+            inner: client.build()?,
+            cassette: Cassette::from_settings(self)?,
+            rate_limit: self.rate_limit,
+            retry: self.retry,
+            limiters,
+            cache_ttl: self.cache_ttl,
+            responses,
+            scraper: "none",
+            refresh: false,
+        })
+    }
+}
+
+/// Clients shared between scrapes
+#[derive(Clone, Default)]
+pub struct ClientPool {
+    clients: Arc<Mutex<HashMap<ClientSettings, Client>>>,
+    limiters: Limiters,
+    responses: ResponseCache,
+    refresh: bool,
+}
+
+impl ClientPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The same pool, but its clients bypass the response cache, ie. when a client
+    /// asked for a fresh scrape
+    pub fn refreshing(&self) -> Self {
+        Self {
+            refresh: true,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the pooled client for these settings, creating it if necessary
+    pub fn get(&self, settings: &ClientSettings) -> Result<Client> {
+        let mut clients = self.clients.lock().unwrap();
+        let client = match clients.get(settings) {
+            Some(client) => client.clone(),
+            None => {
+                debug!("creating new client for {:?}", settings);
+                let client = settings.build(self.limiters.clone(), self.responses.clone())?;
+                clients.insert(settings.clone(), client.clone());
+                client
+            }
+        };
+        Ok(Client {
+            refresh: self.refresh,
+            ..client
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Client {
+    inner: reqwest::Client,
+    cassette: Option<Arc<Cassette>>,
+    rate_limit: RateLimit,
+    retry: RetryPolicy,
+    limiters: Limiters,
+    cache_ttl: std::time::Duration,
+    responses: ResponseCache,
+    /// Name of the scraper using the client, the label of its upstream metrics
+    scraper: &'static str,
+    /// Set if responses must not be answered from the cache
+    refresh: bool,
+}
+
+impl Client {
+    /// The client for use by the named scraper
+    pub fn for_scraper(&self, scraper: &'static str) -> Self {
+        Self {
+            scraper,
+            ..self.clone()
+        }
+    }
+
+    pub fn get<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.request(Method::GET, url)
+    }
+
+    pub fn head<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.request(Method::HEAD, url)
+    }
+
+    pub fn post<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.request(Method::POST, url)
+    }
+
+    pub fn request<U: IntoUrl>(&self, method: Method, url: U) -> RequestBuilder {
+        RequestBuilder {
+            client: self.clone(),
+            inner: self.inner.request(method, url),
+        }
+    }
+
+    fn limiter(&self, host: &str) -> Arc<HostLimiter> {
+        self.limiters.get(host, self.rate_limit)
+    }
+
+    /// Addresses of the host, from the cassette if there is one
+    pub async fn lookup_host(&self, host: &str) -> Result<Vec<IpAddr>> {
+        match &self.cassette {
+            Some(cassette) => cassette.lookup_host(host).await,
+            None => resolve(host).await,
+        }
+    }
+
+    fn is_replay(&self) -> bool {
+        matches!(&self.cassette, Some(c) if c.mode == CassetteMode::Replay)
+    }
+
+    async fn send_with_retry(&self, mut req: reqwest::Request) -> Result<reqwest::Response> {
+        let policy = self.retry;
+        let idempotent = matches!(*req.method(), Method::GET | Method::HEAD);
+        let mut attempt = 1;
+        loop {
+            let next = if idempotent && attempt < policy.attempts && !self.is_replay() {
+                req.try_clone()
+            } else {
+                None
+            };
+            let res = self.execute(req).await;
+            let next = match next {
+                Some(next) => next,
+                None => return res,
+            };
+            let wait = match &res {
+                Ok(resp) => policy.after_response(attempt, resp),
+                Err(e) => policy.after_error(attempt, e),
+            };
+            let wait = match wait {
+                Some(wait) => wait,
+                None => return res,
+            };
+            let reason = match &res {
+                Ok(resp) => resp.status().to_string(),
+                Err(e) => e.to_string(),
+            };
+            UPSTREAM_RETRIES
+                .with_label_values(&[
+                    self.scraper,
+                    host_label(next.url().host_str().unwrap_or_default()),
+                ])
+                .inc();
+            warn!(
+                "attempt {} of {} {} failed with {}, retrying in {:?}",
+                attempt,
+                next.method(),
+                redact(next.url()),
+                reason,
+                wait
+            );
+            tokio::time::sleep(wait).await;
+            req = next;
+            attempt += 1;
+        }
+    }
+
+    async fn execute(&self, req: reqwest::Request) -> Result<reqwest::Response> {
+        trace!("sending {} {}", req.method(), req.url());
+        let host = host_label(req.url().host_str().unwrap_or_default());
+        let _permit = match req.url().host_str() {
+            Some(host) if !self.is_replay() => Some(self.limiter(host).acquire(host).await?),
+            _ => None,
+        };
+        let res = match &self.cassette {
+            Some(cassette) => cassette.handle(&self.inner, req).await,
+            None => self.inner.execute(req).await.map_err(Into::into),
+        };
+        let status = match &res {
+            Ok(resp) => resp.status().as_str().to_string(),
+            Err(_) => "error".to_string(),
+        };
+        UPSTREAM_REQUESTS
+            .with_label_values(&[self.scraper, host, &status])
+            .inc();
+        res
+    }
+}
+
+pub struct RequestBuilder {
+    client: Client,
+    inner: reqwest::RequestBuilder,
+}
+
+impl RequestBuilder {
+    pub fn header<K, V>(self, key: K, value: V) -> Self
+    where
+        HeaderName: TryFrom<K>,
+        <HeaderName as TryFrom<K>>::Error: Into<::http::Error>,
+        HeaderValue: TryFrom<V>,
+        <HeaderValue as TryFrom<V>>::Error: Into<::http::Error>,
+    {
+        Self {
+            inner: self.inner.header(key, value),
+            ..self
+        }
+    }
+
+    pub fn json<T: Serialize + ?Sized>(self, json: &T) -> Self {
+        Self {
+            inner: self.inner.json(json),
+            ..self
+        }
+    }
+
+    /// Sends the request, GET and HEAD requests are retried on transient failures and
+    /// answered from the response cache if possible and the client isn't refreshing
+    pub async fn send(self) -> Result<reqwest::Response> {
+        let client = self.client;
+        let req = self.inner.build()?;
+        let cacheable = matches!(*req.method(), Method::GET | Method::HEAD);
+        if !cacheable {
+            return client.send_with_retry(req).await;
+        }
+        let (method, url) = (req.method().clone(), req.url().clone());
+        if !client.refresh {
+            if let Some(resp) = client.responses.get(&method, &url)? {
+                RESPONSE_CACHE.with_label_values(&["hit"]).inc();
+                return Ok(resp);
+            }
+        }
+        RESPONSE_CACHE.with_label_values(&["miss"]).inc();
+        let resp = client.send_with_retry(req).await?;
+        client
+            .responses
+            .store(&method, &url, resp, client.cache_ttl)
+            .await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CassetteMode {
+    Replay,
+    Record,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct Interaction {
+    method: String,
+    url: String,
+    status: u16,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: String,
+}
+
+/// Method of the interactions recording host lookups, their body lists the addresses
+const LOOKUP: &str = "LOOKUP";
+
+async fn resolve(host: &str) -> Result<Vec<IpAddr>> {
+    let addrs = tokio::net::lookup_host((host, 0))
+        .await
+        .with_context(|| format!("could not resolve {}", host))?;
+    Ok(addrs.map(|x| x.ip()).collect())
+}
+
+/// Recorded set of responses, stored as JSON
+///
+/// In replay mode, requests not found in the cassette fail instead of reaching the network.
+/// In record mode, all requests are made and their responses written to the cassette file.
+/// Host lookups are recorded alongside as interactions with the method `LOOKUP`.
+pub struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+    interactions: Mutex<Vec<Interaction>>,
+}
+
+impl Cassette {
+    fn from_settings(settings: &ClientSettings) -> Result<Option<Arc<Self>>> {
+        let (path, mode) = match (&settings.replay, &settings.record) {
+            (Some(path), _) => (path, CassetteMode::Replay),
+            (None, Some(path)) => (path, CassetteMode::Record),
+            (None, None) => return Ok(None),
+        };
+        let path = PathBuf::from(path);
+        let mut cassettes = CASSETTES.lock().unwrap();
+        if let Some(cassette) = cassettes.get(&path) {
+            if cassette.mode == mode {
+                return Ok(Some(cassette.clone()));
+            }
+        }
+        let interactions: Vec<Interaction> = if path.exists() {
+            let data = std::fs::read_to_string(&path)
+                .with_context(|| format!("could not read cassette {}", path.display()))?;
+            serde_json::from_str(&data)
+                .with_context(|| format!("cassette {} is not valid", path.display()))?
+        } else if mode == CassetteMode::Replay {
+            anyhow::bail!("cassette {} does not exist", path.display())
+        } else {
+            Vec::new()
+        };
+        debug!(
+            "loaded cassette {} with {} interactions",
+            path.display(),
+            interactions.len()
+        );
+        let cassette = Arc::new(Self {
+            path: path.clone(),
+            mode,
+            interactions: Mutex::new(interactions),
+        });
+        cassettes.insert(path, cassette.clone());
+        Ok(Some(cassette))
+    }
+
+    async fn handle(
+        &self,
+        client: &reqwest::Client,
+        req: reqwest::Request,
+    ) -> Result<reqwest::Response> {
+        let method = req.method().to_string();
+        let url = redact(req.url());
+        match self.mode {
+            CassetteMode::Replay => self.find(&method, &url)?.into_response(),
+            CassetteMode::Record => {
+                debug!("recording {} {}", method, url);
+                let resp = client.execute(req).await?;
+                let interaction = Interaction {
+                    method,
+                    url,
+                    status: resp.status().as_u16(),
+                    headers: resp
+                        .headers()
+                        .iter()
+                        .filter(|(name, _)| {
+                            !matches!(
+                                name.as_str(),
+                                "content-encoding"
+                                    | "content-length"
+                                    | "transfer-encoding"
+                                    | "set-cookie"
+                            )
+                        })
+                        .flat_map(|(name, value)| {
+                            value
+                                .to_str()
+                                .map(|value| (name.to_string(), value.to_string()))
+                        })
+                        .collect(),
+                    body: String::from_utf8_lossy(&resp.bytes().await?).to_string(),
+                };
+                let response = interaction.clone().into_response();
+                self.record(interaction)?;
+                response
+            }
+        }
+    }
+
+    fn find(&self, method: &str, url: &str) -> Result<Interaction> {
+        let interaction = self
+            .interactions
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|x| x.method == method && x.url == url)
+            .cloned();
+        match interaction {
+            Some(interaction) => {
+                trace!("replaying {} {}", method, url);
+                Ok(interaction)
+            }
+            None => anyhow::bail!(
+                "no recorded response for {} {} in {}",
+                method,
+                url,
+                self.path.display()
+            ),
+        }
+    }
+
+    fn record(&self, interaction: Interaction) -> Result<()> {
+        let mut interactions = self.interactions.lock().unwrap();
+        interactions.retain(|x| x.method != interaction.method || x.url != interaction.url);
+        interactions.push(interaction);
+        std::fs::write(&self.path, serde_json::to_string_pretty(&*interactions)?)
+            .with_context(|| format!("could not write cassette {}", self.path.display()))
+    }
+
+    async fn lookup_host(&self, host: &str) -> Result<Vec<IpAddr>> {
+        match self.mode {
+            CassetteMode::Replay => {
+                let interaction = self.find(LOOKUP, host)?;
+                serde_json::from_str(&interaction.body)
+                    .with_context(|| format!("recorded lookup of {} is not valid", host))
+            }
+            CassetteMode::Record => {
+                debug!("recording lookup of {}", host);
+                let addrs = resolve(host).await?;
+                self.record(Interaction {
+                    method: LOOKUP.to_string(),
+                    url: host.to_string(),
+                    status: 200,
+                    headers: BTreeMap::new(),
+                    body: serde_json::to_string(&addrs)?,
+                })?;
+                Ok(addrs)
+            }
+        }
+    }
+}
+
+impl Interaction {
+    fn into_response(self) -> Result<reqwest::Response> {
+        let mut resp = ::http::Response::builder().status(self.status);
+        for (name, value) in self.headers.iter() {
+            resp = resp.header(name.as_str(), value.as_str());
+        }
+        Ok(resp.body(self.body)?.into())
+    }
+}
+
+fn redact(url: &url::Url) -> String {
+    if !url
+        .query_pairs()
+        .any(|(k, _)| REDACTED_PARAMS.contains(&&*k))
+    {
+        return url.to_string();
+    }
+    let mut url = url.clone();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| {
+            if REDACTED_PARAMS.contains(&&*k) {
+                (k.to_string(), "REDACTED".to_string())
+            } else {
+                (k.to_string(), v.to_string())
+            }
+        })
+        .collect();
+    url.query_pairs_mut().clear().extend_pairs(pairs);
+    url.to_string()
+}
+
+/// Configuration replaying the fixture of the given name from `tests/fixtures`
+///
+/// The fixtures are synthetic, see `tests/fixtures/README.md`. Set `RECORD_FIXTURES`
+/// to record the fixture from the live sites instead.
+#[cfg(test)]
+pub fn fixture(name: &str) -> crate::ConfigurationBuilder {
+    let path = format!(
+        "{}/tests/fixtures/{}.json",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    );
+    if std::env::var("RECORD_FIXTURES").is_ok() {
+        Configuration::builder().record(path)
+    } else {
+        Configuration::builder().replay(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_redact_api_key() -> Result<()> {
+        let url = url::Url::from_str("https://api.tumblr.com/v2/blog/x/posts?id=1&api_key=secret")?;
+        assert_eq!(
+            "https://api.tumblr.com/v2/blog/x/posts?id=1&api_key=REDACTED",
+            redact(&url)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_unknown_request_fails() -> Result<()> {
+        let config = fixture("raw").build();
+        let client = ClientPool::new().get(&ClientSettings::new(&config))?;
+        let resp = tokio_test::block_on(client.get("https://example.com/not-recorded").send());
+        assert!(resp.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pool_shares_clients() -> Result<()> {
+        let config = Configuration::default();
+        let pool = ClientPool::new();
+        pool.get(&ClientSettings::new(&config))?;
+        pool.get(&ClientSettings::new(&config))?;
+        assert_eq!(1, pool.clients.lock().unwrap().len());
+        pool.get(&ClientSettings::new(&config).redirect(Redirect::Limited(5)))?;
+        assert_eq!(2, pool.clients.lock().unwrap().len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_upstream_metrics_are_labeled_by_scraper() -> Result<()> {
+        let config = fixture("raw").build();
+        let client = ClientPool::new()
+            .get(&ClientSettings::new(&config))?
+            .for_scraper("labeled");
+        let requests = || {
+            UPSTREAM_REQUESTS
+                .with_label_values(&["labeled", "other", "200"])
+                .get()
+        };
+        let before = requests();
+        let url = "https://static.manebooru.art/img/view/2021/3/20/4010154.png";
+        tokio_test::block_on(client.head(url).send())?;
+        assert_eq!(before + 1, requests());
+        Ok(())
+    }
+
+    #[test]
+    fn test_refreshing_pool_bypasses_response_cache() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("scraper-refresh-{}.json", std::process::id()));
+        let url = "https://example.com/refresh";
+        let interaction = |body: &str| Interaction {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            status: 200,
+            headers: BTreeMap::new(),
+            body: body.to_string(),
+        };
+        std::fs::write(&path, serde_json::to_string(&[interaction("old")])?)?;
+        let config = Configuration::builder()
+            .replay(path.to_string_lossy())
+            .build();
+        let pool = ClientPool::new();
+        let client = pool.get(&ClientSettings::new(&config))?;
+        let fetch = |client: &Client| {
+            tokio_test::block_on(async {
+                Ok::<_, anyhow::Error>(client.get(url).send().await?.text().await?)
+            })
+        };
+        assert_eq!("old", fetch(&client)?);
+        if let Some(cassette) = &client.cassette {
+            *cassette.interactions.lock().unwrap() = vec![interaction("new")];
+        }
+        assert_eq!("old", fetch(&client)?);
+        let refreshing = pool.refreshing().get(&ClientSettings::new(&config))?;
+        assert_eq!("new", fetch(&refreshing)?);
+        assert_eq!("new", fetch(&client)?);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
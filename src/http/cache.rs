@@ -0,0 +1,185 @@
+//! Cache of upstream responses shared by all clients of a pool
+//!
+//! Successful GET and HEAD responses are kept for the client's configured duration, or
+//! shorter if upstream asks for it via Cache-Control.
+
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::trace;
+use reqwest::header::{HeaderMap, CACHE_CONTROL};
+use reqwest::{Method, StatusCode};
+
+/// Upper bound of the cached bodies in bytes
+const MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    expires: Instant,
+}
+
+#[derive(Clone)]
+pub(super) struct ResponseCache {
+    responses: moka::sync::Cache<String, Arc<CachedResponse>>,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self {
+            responses: moka::sync::CacheBuilder::new(MAX_SIZE)
+                .weigher(|key: &String, value: &Arc<CachedResponse>| {
+                    (key.len() + value.body.len())
+                        .try_into()
+                        .unwrap_or(u32::MAX)
+                })
+                .build(),
+        }
+    }
+}
+
+impl ResponseCache {
+    fn key(method: &Method, url: &url::Url) -> String {
+        format!("{} {}", method, url)
+    }
+
+    pub(super) fn get(&self, method: &Method, url: &url::Url) -> Result<Option<reqwest::Response>> {
+        let key = Self::key(method, url);
+        let cached = match self.responses.get(&key) {
+            Some(cached) if cached.expires > Instant::now() => cached,
+            Some(_) => {
+                self.responses.invalidate(&key);
+                return Ok(None);
+            }
+            None => return Ok(None),
+        };
+        trace!("answering {} from cache", key);
+        let mut resp = ::http::Response::builder().status(cached.status);
+        if let Some(headers) = resp.headers_mut() {
+            *headers = cached.headers.clone();
+        }
+        Ok(Some(resp.body(cached.body.clone())?.into()))
+    }
+
+    /// Caches the response if upstream allows it, returns an equivalent response
+    pub(super) async fn store(
+        &self,
+        method: &Method,
+        url: &url::Url,
+        resp: reqwest::Response,
+        ttl: Duration,
+    ) -> Result<reqwest::Response> {
+        let ttl = match freshness(resp.status(), resp.headers(), ttl) {
+            Some(ttl) => ttl,
+            None => return Ok(resp),
+        };
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        let body = resp.bytes().await?.to_vec();
+        let mut rebuilt = ::http::Response::builder().status(status);
+        if let Some(rebuilt) = rebuilt.headers_mut() {
+            *rebuilt = headers.clone();
+        }
+        let rebuilt = rebuilt.body(body.clone())?.into();
+        self.responses.insert(
+            Self::key(method, url),
+            Arc::new(CachedResponse {
+                status,
+                headers,
+                body,
+                expires: Instant::now() + ttl,
+            }),
+        );
+        Ok(rebuilt)
+    }
+}
+
+/// How long a response may be cached, None if it may not be cached at all
+fn freshness(status: StatusCode, headers: &HeaderMap, ttl: Duration) -> Option<Duration> {
+    let cacheable = status.is_success()
+        || status.is_redirection()
+        || status == StatusCode::NOT_FOUND
+        || status == StatusCode::GONE;
+    if !cacheable || ttl.is_zero() {
+        return None;
+    }
+    let mut ttl = ttl;
+    for value in headers.get_all(CACHE_CONTROL) {
+        let value = value.to_str().ok()?;
+        for directive in value.split(',').map(|x| x.trim().to_ascii_lowercase()) {
+            match directive.split_once('=') {
+                Some(("max-age", secs)) => {
+                    let secs: u64 = secs.trim_matches('"').parse().ok()?;
+                    ttl = ttl.min(Duration::from_secs(secs));
+                }
+                None if matches!(&*directive, "no-store" | "no-cache" | "private") => return None,
+                _ => (),
+            }
+        }
+    }
+    Some(ttl).filter(|x| !x.is_zero())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn cache_control(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_freshness() {
+        let ttl = Duration::from_secs(600);
+        assert_eq!(Some(ttl), freshness(StatusCode::OK, &HeaderMap::new(), ttl));
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            freshness(StatusCode::OK, &cache_control("public, max-age=60"), ttl)
+        );
+        assert_eq!(
+            Some(ttl),
+            freshness(StatusCode::OK, &cache_control("max-age=86400"), ttl)
+        );
+        assert_eq!(
+            None,
+            freshness(StatusCode::OK, &cache_control("no-store"), ttl)
+        );
+        assert_eq!(
+            None,
+            freshness(StatusCode::OK, &cache_control("max-age=0"), ttl)
+        );
+        assert_eq!(
+            None,
+            freshness(StatusCode::SERVICE_UNAVAILABLE, &HeaderMap::new(), ttl)
+        );
+    }
+
+    #[test]
+    fn test_cached_response_is_replayed() -> Result<()> {
+        let cache = ResponseCache::default();
+        let url = url::Url::parse("https://example.com/main.js")?;
+        let resp: reqwest::Response = ::http::Response::builder()
+            .status(200)
+            .header("content-type", "text/javascript")
+            .body("bearer")
+            .unwrap()
+            .into();
+        let ttl = Duration::from_secs(60);
+        let resp = tokio_test::block_on(cache.store(&Method::GET, &url, resp, ttl))?;
+        assert_eq!("bearer", tokio_test::block_on(resp.text())?);
+        assert!(cache.get(&Method::HEAD, &url)?.is_none());
+        let resp = cache
+            .get(&Method::GET, &url)?
+            .expect("response should be cached");
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("text/javascript", resp.headers()["content-type"]);
+        assert_eq!("bearer", tokio_test::block_on(resp.text())?);
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+//! Per host budget for outgoing requests
+//!
+//! Every upstream host gets a token bucket and a cap on concurrent requests. Requests
+//! exceeding the budget wait in a bounded queue, once that is full they are rejected
+//! with [`ErrorKind::RateLimited`].
+//!
+//! Limiters of hosts idle for [`IDLE_TIMEOUT`] are dropped, as are the least used ones
+//! beyond [`MAX_HOSTS`], since any host can be submitted for scraping.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::debug;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::{ErrorKind, ScrapeError};
+
+/// Hosts whose limiters are kept at most
+const MAX_HOSTS: u64 = 10_000;
+/// How long the limiter of a host is kept after its last request, longer than any
+/// request runs so no limiter is dropped while requests are in flight
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Request budget applied to each upstream host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RateLimit {
+    /// Requests per minute, 0 disables the token bucket
+    pub per_minute: u32,
+    /// Requests that may be made at once after the host was idle
+    pub burst: u32,
+    /// Requests running at the same time, 0 disables the cap
+    pub max_in_flight: u32,
+    /// Requests waiting for budget before further ones are rejected
+    pub max_queued: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            per_minute: 120,
+            burst: 20,
+            max_in_flight: 8,
+            max_queued: 64,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub(super) struct HostLimiter {
+    limit: RateLimit,
+    bucket: Mutex<Bucket>,
+    in_flight: Arc<Semaphore>,
+    queued: AtomicU32,
+}
+
+/// Held while a request is running, frees the in flight slot when dropped
+pub(super) struct Permit {
+    _in_flight: Option<OwnedSemaphorePermit>,
+}
+
+struct Queued<'a>(&'a AtomicU32);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Limiters by host, shared by all clients of a pool
+#[derive(Clone)]
+pub(super) struct Limiters {
+    limiters: moka::sync::Cache<String, Arc<HostLimiter>>,
+}
+
+impl Default for Limiters {
+    fn default() -> Self {
+        Self {
+            limiters: moka::sync::CacheBuilder::new(MAX_HOSTS)
+                .time_to_idle(IDLE_TIMEOUT)
+                .build(),
+        }
+    }
+}
+
+impl Limiters {
+    /// Limiter of the host, created with the limit of the first client reaching it
+    pub(super) fn get(&self, host: &str, limit: RateLimit) -> Arc<HostLimiter> {
+        self.limiters
+            .get_with(host.to_string(), || Arc::new(HostLimiter::new(limit)))
+    }
+}
+
+impl HostLimiter {
+    pub(super) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            bucket: Mutex::new(Bucket {
+                tokens: limit.burst.max(1) as f64,
+                updated: Instant::now(),
+            }),
+            in_flight: Arc::new(Semaphore::new(limit.max_in_flight as usize)),
+            queued: AtomicU32::new(0),
+        }
+    }
+
+    /// Waits until the host budget allows another request
+    pub(super) async fn acquire(&self, host: &str) -> Result<Permit> {
+        let queued = Queued(&self.queued);
+        if queued.0.fetch_add(1, Ordering::SeqCst) >= self.limit.max_queued {
+            anyhow::bail!(ScrapeError::new(
+                ErrorKind::RateLimited,
+                format!("request budget for {} exhausted", host)
+            ));
+        }
+        let in_flight = if self.limit.max_in_flight > 0 {
+            Some(self.in_flight.clone().acquire_owned().await?)
+        } else {
+            None
+        };
+        let wait = self.reserve();
+        if !wait.is_zero() {
+            debug!("delaying request to {} by {:?}", host, wait);
+            tokio::time::sleep(wait).await;
+        }
+        drop(queued);
+        Ok(Permit {
+            _in_flight: in_flight,
+        })
+    }
+
+    /// Takes a token from the bucket and returns how long to wait until it is available
+    fn reserve(&self) -> Duration {
+        if self.limit.per_minute == 0 {
+            return Duration::ZERO;
+        }
+        let rate = self.limit.per_minute as f64 / 60.0;
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.limit.burst.max(1) as f64);
+        bucket.updated = now;
+        bucket.tokens -= 1.0;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bucket_delays_after_burst() {
+        let limiter = HostLimiter::new(RateLimit {
+            per_minute: 60,
+            burst: 2,
+            ..RateLimit::default()
+        });
+        assert_eq!(Duration::ZERO, limiter.reserve());
+        assert_eq!(Duration::ZERO, limiter.reserve());
+        let wait = limiter.reserve();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        let wait = limiter.reserve();
+        assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_limiters_are_shared_per_host() {
+        let limiters = Limiters::default();
+        let first = limiters.get("example.com", RateLimit::default());
+        let again = limiters.get("example.com", RateLimit::default());
+        assert!(Arc::ptr_eq(&first, &again));
+        let other = limiters.get("example.org", RateLimit::default());
+        assert!(!Arc::ptr_eq(&first, &other));
+    }
+
+    #[test]
+    fn test_full_queue_rejects() -> Result<()> {
+        let limiter = HostLimiter::new(RateLimit {
+            per_minute: 0,
+            max_in_flight: 1,
+            max_queued: 1,
+            ..RateLimit::default()
+        });
+        tokio_test::block_on(async {
+            let first = limiter.acquire("example.com").await?;
+            let second = limiter.acquire("example.com");
+            futures_util::pin_mut!(second);
+            assert!(futures_util::poll!(&mut second).is_pending());
+            let third = limiter.acquire("example.com").await;
+            assert_eq!(
+                Some(ErrorKind::RateLimited),
+                third.err().map(|e| ErrorKind::from_error(&e))
+            );
+            drop(first);
+            second.await?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,134 @@
+//! Retrying idempotent requests on transient upstream failures
+
+use std::time::{Duration, SystemTime};
+
+use rand::Rng;
+use reqwest::{header::RETRY_AFTER, StatusCode};
+
+/// How often and how long to wait before repeating a failed GET or HEAD request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RetryPolicy {
+    /// Attempts including the first one, 1 disables retries
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for each further one
+    pub backoff: Duration,
+    /// Upper bound of the delay, a longer Retry-After gives up instead
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the next attempt after the given failed attempt, starting at 1
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        // somewhere between half and the full delay, so failed requests don't come back in lockstep
+        delay / 2 + delay.mul_f64(rand::thread_rng().gen_range(0.0..0.5))
+    }
+
+    /// Returns how long to wait before retrying, or None if the response should be kept
+    pub(super) fn after_response(
+        &self,
+        attempt: u32,
+        resp: &reqwest::Response,
+    ) -> Option<Duration> {
+        if !is_transient(resp.status()) {
+            return None;
+        }
+        let backoff = self.backoff(attempt);
+        match retry_after(resp) {
+            Some(wait) if wait > self.max_backoff => None,
+            Some(wait) => Some(wait.max(backoff)),
+            None => Some(backoff),
+        }
+    }
+
+    /// Returns how long to wait before retrying, or None if the error is not transient
+    pub(super) fn after_error(&self, attempt: u32, e: &anyhow::Error) -> Option<Duration> {
+        let e = e.downcast_ref::<reqwest::Error>()?;
+        if e.is_timeout() || e.is_connect() || e.is_request() {
+            Some(self.backoff(attempt))
+        } else {
+            None
+        }
+    }
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Parses Retry-After, either in seconds or as a HTTP date
+fn retry_after(resp: &reqwest::Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn response(status: u16, retry_after: Option<&str>) -> reqwest::Response {
+        let mut resp = ::http::Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            resp = resp.header(RETRY_AFTER, retry_after);
+        }
+        resp.body("").unwrap().into()
+    }
+
+    #[test]
+    fn test_backoff_grows_with_jitter() {
+        let policy = RetryPolicy {
+            attempts: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+        };
+        let first = policy.backoff(1);
+        assert!(first >= Duration::from_millis(50) && first < Duration::from_millis(100));
+        let second = policy.backoff(2);
+        assert!(second >= Duration::from_millis(100) && second < Duration::from_millis(200));
+        let capped = policy.backoff(10);
+        assert!(capped >= Duration::from_millis(150) && capped < Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let policy = RetryPolicy::default();
+        assert_eq!(None, policy.after_response(1, &response(404, None)));
+        assert!(policy.after_response(1, &response(503, None)).is_some());
+        assert_eq!(
+            Some(Duration::from_secs(2)),
+            policy.after_response(1, &response(429, Some("2")))
+        );
+        assert_eq!(None, policy.after_response(1, &response(429, Some("3600"))));
+        assert!(policy
+            .after_response(1, &response(503, Some("Wed, 21 Oct 2015 07:28:00 GMT")))
+            .is_some());
+    }
+}
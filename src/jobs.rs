@@ -0,0 +1,394 @@
+//! Asynchronous scrapes for slow sources
+//!
+//! `POST /jobs` takes the same body as `/images/scrape` and answers right away with a
+//! job id, the result can then be polled at `/jobs/{id}`. While a job for a canonical
+//! URL is pending, submitting the URL again returns that job instead of a new one.
+//! Results go through the result cache like those of synchronous scrapes.
+//!
+//! With a `callback_url` in the body, the finished job is also delivered there by
+//! [`crate::webhook`], once per submission that asked for it.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, Query},
+    http, response, Extension, Json,
+};
+use log::{debug, error};
+use rand::Rng;
+use serde::Serialize;
+
+use scraper::{ErrorKind, ScrapeResult, ScrapeResultError};
+
+use crate::web::{cache_key, json_response, scrape_inner, status_for, CacheParams, ScrapeRequest};
+use crate::{Configuration, State};
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    /// Finished, with the status a synchronous scrape would have answered with
+    Done {
+        http_status: u16,
+        result: ScrapeResult,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct Job {
+    pub id: String,
+    /// Canonical URL being scraped
+    pub url: String,
+    #[serde(flatten)]
+    pub status: JobStatus,
+}
+
+struct Pending {
+    id: String,
+    /// Webhooks to deliver the finished job to
+    callbacks: Vec<url::Url>,
+}
+
+#[derive(Clone)]
+pub struct Jobs {
+    /// Pending and finished jobs by id, finished ones are kept for `JOB_RETENTION`
+    jobs: moka::sync::Cache<String, Job>,
+    /// Pending jobs by canonical URL
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+    max_pending: usize,
+}
+
+impl Jobs {
+    pub fn new(config: &Configuration) -> Self {
+        Self {
+            jobs: moka::sync::CacheBuilder::new(10_000)
+                .time_to_live(Duration::from_secs(config.job_retention))
+                .build(),
+            pending: Arc::default(),
+            max_pending: config.job_max_pending,
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.get(&id.to_string())
+    }
+
+    /// Returns the pending job for the URL or creates one, the flag is set if the
+    /// caller has to run the new job, None if too many jobs are pending
+    fn submit(&self, url: &str, callback: Option<url::Url>) -> Option<(Job, bool)> {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(entry) = pending.get_mut(url) {
+            if let Some(job) = self.get(&entry.id) {
+                entry.callbacks.extend(callback);
+                return Some((job, false));
+            }
+        }
+        if pending.len() >= self.max_pending {
+            return None;
+        }
+        let id = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+        let job = Job {
+            id: id.clone(),
+            url: url.to_string(),
+            status: JobStatus::Pending,
+        };
+        pending.insert(
+            url.to_string(),
+            Pending {
+                id,
+                callbacks: callback.into_iter().collect(),
+            },
+        );
+        self.jobs.insert(job.id.clone(), job.clone());
+        Some((job, true))
+    }
+
+    /// Stores the result, returns the finished job and where to deliver it
+    ///
+    /// The pending entry of the URL is only removed if it is still this job's, it is
+    /// replaced by a newer job if this one expired from `jobs` before finishing.
+    fn finish(
+        &self,
+        job: Job,
+        status: http::StatusCode,
+        result: ScrapeResult,
+    ) -> (Job, Vec<url::Url>) {
+        let callbacks = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&job.url) {
+                Some(entry) if entry.id == job.id => pending
+                    .remove(&job.url)
+                    .map(|x| x.callbacks)
+                    .unwrap_or_default(),
+                _ => Vec::new(),
+            }
+        };
+        let job = Job {
+            status: JobStatus::Done {
+                http_status: status.as_u16(),
+                result,
+            },
+            ..job
+        };
+        self.jobs.insert(job.id.clone(), job.clone());
+        (job, callbacks)
+    }
+}
+
+/// Finishes the job with the result of the scrape and delivers it
+///
+/// The scrape runs in its own task, so the job is finished with an internal error
+/// rather than left pending if it panics.
+async fn run<F>(state: Arc<State>, job: Job, scrape: F)
+where
+    F: Future<Output = (http::StatusCode, ScrapeResult)> + Send + 'static,
+{
+    let (status, result) = match tokio::spawn(scrape).await {
+        Ok(scraped) => scraped,
+        Err(e) => {
+            error!("job {} for {} failed: {}", job.id, job.url, e);
+            (
+                status_for(ErrorKind::Internal),
+                ScrapeResult::Err(ScrapeResultError::new(ErrorKind::Internal, "job failed")),
+            )
+        }
+    };
+    let (job, callbacks) = state.jobs.finish(job, status, result);
+    for callback in callbacks {
+        state.webhooks.enqueue(callback, &job);
+    }
+}
+
+fn job_response(status: http::StatusCode, job: &Job) -> response::Response<String> {
+    let mut res = json_response(status, job);
+    if let Ok(location) = http::HeaderValue::from_str(&format!("/jobs/{}", job.id)) {
+        res.headers_mut().insert(http::header::LOCATION, location);
+    }
+    res
+}
+
+/// Submits a scrape job
+///
+/// If a `callback_url` is given, the finished job is POSTed there as `/jobs/{id}` would
+/// return it, its `result` is the `ScrapeResult`. The body is signed in the
+/// `X-Scraper-Signature` header.
+#[utoipa::path(
+    post,
+    path = "/jobs",
+    request_body = ScrapeRequest,
+    params(CacheParams),
+    responses(
+        (status = 202, description = "Pending or, if deduplicated, already finished job", body = Job,
+            headers(("location" = String, description = "Where to poll the job"))),
+        (status = 400, description = "Invalid or non-public `callback_url`, or callbacks not enabled", body = ScrapeResultError),
+        (status = 429, description = "Too many pending jobs or request budget of the API key exhausted", body = ScrapeResultError),
+    )
+)]
+pub async fn submit(
+    Json(scrape_req): Json<ScrapeRequest>,
+    Query(params): Query<CacheParams>,
+    headers: http::HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+) -> response::Response<String> {
+    let callback = match scrape_req
+        .callback_url
+        .as_deref()
+        .map(|x| state.webhooks.callback_url(x))
+    {
+        Some(Ok(callback)) if !state.webhooks.is_enabled() => {
+            debug!("refusing callback to {} without WEBHOOK_SECRET", callback);
+            return json_response(
+                http::StatusCode::BAD_REQUEST,
+                &ScrapeResultError::new(ErrorKind::InvalidUrl, "callbacks are not enabled"),
+            );
+        }
+        Some(Err(message)) => {
+            return json_response(
+                http::StatusCode::BAD_REQUEST,
+                &ScrapeResultError::new(ErrorKind::InvalidUrl, message),
+            )
+        }
+        Some(Ok(callback)) => Some(callback),
+        None => None,
+    };
+    let url = cache_key(&state, scrape_req.url);
+    let (job, new) = match state.jobs.submit(&url, callback) {
+        Some(submitted) => submitted,
+        None => {
+            return json_response(
+                http::StatusCode::TOO_MANY_REQUESTS,
+                &ScrapeResultError::new(ErrorKind::RateLimited, "too many pending jobs"),
+            )
+        }
+    };
+    if new {
+        debug!("starting job {} for {}", job.id, url);
+        let refresh = params.refresh(&headers);
+        let scrape = {
+            let state = state.clone();
+            let url = job.url.clone();
+            async move { scrape_inner(&state, ScrapeRequest::new(url), refresh).await }
+        };
+        tokio::spawn(run(state, job.clone(), scrape));
+    }
+    job_response(http::StatusCode::ACCEPTED, &job)
+}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    params(("id" = String, Path, description = "Id of the job")),
+    responses(
+        (status = 200, description = "Pending or finished job, a finished one has the `http_status` `/images/scrape` would have answered with", body = Job),
+        (status = 404, description = "Unknown or expired job", body = ScrapeResultError),
+    )
+)]
+pub async fn poll(
+    Path(id): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> response::Response<String> {
+    match state.jobs.get(&id) {
+        Some(job) => job_response(http::StatusCode::OK, &job),
+        None => json_response(
+            http::StatusCode::NOT_FOUND,
+            &ScrapeResultError::new(ErrorKind::NotFound, "job does not exist"),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_jobs_are_deduplicated() {
+        let jobs = Jobs::new(&Configuration {
+            job_max_pending: 2,
+            ..Configuration::default()
+        });
+        let (first, new) = jobs.submit("https://example.com/a.png", None).unwrap();
+        assert!(new);
+        assert_eq!(JobStatus::Pending, first.status);
+        let (again, new) = jobs.submit("https://example.com/a.png", None).unwrap();
+        assert!(!new);
+        assert_eq!(first.id, again.id);
+        let (other, _) = jobs.submit("https://example.com/b.png", None).unwrap();
+        assert!(jobs.submit("https://example.com/c.png", None).is_none());
+        jobs.finish(other, http::StatusCode::OK, ScrapeResult::None);
+        let (after, new) = jobs.submit("https://example.com/a.png", None).unwrap();
+        assert!(!new);
+        assert_eq!(first.id, after.id);
+        let (_, callbacks) = jobs.finish(first.clone(), http::StatusCode::OK, ScrapeResult::None);
+        assert!(callbacks.is_empty());
+        let done = jobs.get(&first.id).unwrap();
+        assert_eq!(
+            JobStatus::Done {
+                http_status: 200,
+                result: ScrapeResult::None
+            },
+            done.status
+        );
+        let (fresh, new) = jobs.submit("https://example.com/a.png", None).unwrap();
+        assert!(new);
+        assert_ne!(first.id, fresh.id);
+    }
+
+    #[tokio::test]
+    async fn test_panicking_job_is_finished() -> anyhow::Result<()> {
+        let state = Arc::new(State::new(Configuration::default())?);
+        let (job, _) = state
+            .jobs
+            .submit("https://example.com/a.png", None)
+            .unwrap();
+        run(state.clone(), job.clone(), async { panic!("scraper bug") }).await;
+        let done = state.jobs.get(&job.id).unwrap();
+        assert_eq!(
+            JobStatus::Done {
+                http_status: 500,
+                result: ScrapeResult::Err(ScrapeResultError::new(
+                    ErrorKind::Internal,
+                    "job failed"
+                )),
+            },
+            done.status
+        );
+        let (_, new) = state
+            .jobs
+            .submit("https://example.com/a.png", None)
+            .unwrap();
+        assert!(new);
+        Ok(())
+    }
+
+    #[test]
+    fn test_callbacks_of_deduplicated_jobs() {
+        let jobs = Jobs::new(&Configuration::default());
+        let hook = |x: &str| url::Url::parse(x).unwrap();
+        let (job, _) = jobs
+            .submit(
+                "https://example.com/a.png",
+                Some(hook("https://a.test/hook")),
+            )
+            .unwrap();
+        jobs.submit("https://example.com/a.png", None).unwrap();
+        jobs.submit(
+            "https://example.com/a.png",
+            Some(hook("https://b.test/hook")),
+        )
+        .unwrap();
+        let (done, callbacks) = jobs.finish(job.clone(), http::StatusCode::OK, ScrapeResult::None);
+        assert_eq!(job.id, done.id);
+        assert_eq!(
+            vec![hook("https://a.test/hook"), hook("https://b.test/hook")],
+            callbacks
+        );
+    }
+
+    #[test]
+    fn test_expired_job_keeps_newer_pending_job() {
+        let jobs = Jobs::new(&Configuration::default());
+        let hook = |x: &str| url::Url::parse(x).unwrap();
+        let (old, _) = jobs
+            .submit(
+                "https://example.com/a.png",
+                Some(hook("https://a.test/hook")),
+            )
+            .unwrap();
+        jobs.jobs.invalidate(&old.id);
+        let (job, new) = jobs
+            .submit(
+                "https://example.com/a.png",
+                Some(hook("https://b.test/hook")),
+            )
+            .unwrap();
+        assert!(new);
+        assert_ne!(old.id, job.id);
+        let (_, callbacks) = jobs.finish(old, http::StatusCode::OK, ScrapeResult::None);
+        assert!(callbacks.is_empty());
+        let (again, new) = jobs.submit("https://example.com/a.png", None).unwrap();
+        assert!(!new);
+        assert_eq!(job.id, again.id);
+        let (_, callbacks) = jobs.finish(job, http::StatusCode::OK, ScrapeResult::None);
+        assert_eq!(vec![hook("https://b.test/hook")], callbacks);
+    }
+
+    #[test]
+    fn test_job_serialization() -> anyhow::Result<()> {
+        let job = Job {
+            id: "1".to_string(),
+            url: "https://example.com/a.png".to_string(),
+            status: JobStatus::Done {
+                http_status: 400,
+                result: ScrapeResult::Err(ScrapeResultError::new(ErrorKind::InvalidUrl, "bad")),
+            },
+        };
+        assert_eq!(
+            r#"{"id":"1","url":"https://example.com/a.png","status":"done","http_status":400,"result":{"errors":["bad"],"code":"invalid_url"}}"#,
+            serde_json::to_string(&job)?
+        );
+        Ok(())
+    }
+}
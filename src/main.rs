@@ -1,16 +1,29 @@
 use std::sync::Arc;
 
 use anyhow::Result;
-use axum::{routing::get, Extension};
+use axum::{
+    routing::{get, post},
+    Extension,
+};
 use envconfig::Envconfig;
 use flexi_logger::LoggerHandle;
 use lazy_static::lazy_static;
 use log::{info, trace, LevelFilter};
 use std::sync::Mutex;
 
-mod camo;
-mod scraper;
+mod admin;
+mod apikey;
+mod cache;
+mod cors;
+mod csrf;
+mod exporter;
+mod health;
+mod jobs;
+mod openapi;
 mod web;
+mod webhook;
+
+use crate::cache::ResultCache;
 
 #[derive(Envconfig, Clone, securefmt::Debug)]
 pub struct Configuration {
@@ -20,70 +33,119 @@ pub struct Configuration {
     #[envconfig(from = "ALLOWED_ORIGINS", default = "localhost,localhost:8080")]
     #[sensitive]
     allowed_origins: String,
+    #[envconfig(from = "CORS_ALLOWED_METHODS", default = "GET,POST")]
+    cors_allowed_methods: String,
+    #[envconfig(from = "CORS_ALLOWED_HEADERS", default = "content-type,x-csrf-token")]
+    cors_allowed_headers: String,
+    #[envconfig(from = "CORS_MAX_AGE", default = "600")]
+    cors_max_age: u64,
     #[envconfig(from = "CHECK_CSRF_PRESENCE", default = "false")]
     check_csrf_presence: bool,
-    #[envconfig(from = "TUMBLR_API_KEY")]
-    #[sensitive]
-    tumblr_api_key: Option<String>,
-    #[envconfig(from = "HTTP_PROXY")]
+    #[envconfig(from = "CSRF_SECRET")]
     #[sensitive]
-    proxy_url: Option<String>,
-    #[envconfig(from = "CAMO_KEY")]
-    #[sensitive]
-    camo_key: Option<String>,
-    #[envconfig(from = "CAMO_HOST")]
-    camo_host: Option<String>,
+    csrf_secret: Option<String>,
+    #[envconfig(from = "CSRF_SALT", default = "scraper csrf")]
+    csrf_salt: String,
+    #[envconfig(from = "CSRF_MAX_AGE", default = "86400")]
+    csrf_max_age: u64,
     #[envconfig(from = "ENABLE_GET_REQUEST", default = "false")]
     enable_get_request: bool,
-    #[envconfig(from = "PREFERRED_NITTER_INSTANCE_HOST")]
-    preferred_nitter_instance_host: Option<String>,
     #[envconfig(from = "LOG_LEVEL", default = "INFO")]
     log_level: LevelFilter,
     #[envconfig(from = "ALLOW_EMPTY_ORIGIN", default = "false")]
     allow_empty_origin: bool,
+    #[envconfig(from = "LEGACY_STATUS_CODES", default = "false")]
+    legacy_status_codes: bool,
+    #[envconfig(from = "ADMIN_TOKEN")]
+    #[sensitive]
+    admin_token: Option<String>,
+    #[envconfig(from = "API_KEYS")]
+    #[sensitive]
+    api_keys: Option<String>,
+    #[envconfig(from = "API_KEY_FILE")]
+    api_key_file: Option<String>,
+    #[envconfig(from = "API_KEY_RATE_LIMIT", default = "60")]
+    api_key_rate_limit: u32,
+    #[envconfig(from = "API_KEYS_BYPASS_ORIGIN", default = "false")]
+    api_keys_bypass_origin: bool,
+    #[envconfig(from = "CANARY_URLS")]
+    canary_urls: Option<String>,
+    #[envconfig(from = "CANARY_INTERVAL", default = "900")]
+    canary_interval: u64,
+    #[envconfig(from = "BATCH_MAX_URLS", default = "50")]
+    batch_max_urls: usize,
+    #[envconfig(from = "BATCH_CONCURRENCY", default = "8")]
+    batch_concurrency: usize,
+    #[envconfig(from = "JOB_MAX_PENDING", default = "256")]
+    job_max_pending: usize,
+    #[envconfig(from = "JOB_RETENTION", default = "600")]
+    job_retention: u64,
+    #[envconfig(from = "WEBHOOK_SECRET")]
+    #[sensitive]
+    webhook_secret: Option<String>,
+    #[envconfig(from = "WEBHOOK_QUEUE_SIZE", default = "1000")]
+    webhook_queue_size: usize,
+    #[envconfig(from = "WEBHOOK_WORKERS", default = "4")]
+    webhook_workers: usize,
+    #[envconfig(from = "WEBHOOK_ATTEMPTS", default = "5")]
+    webhook_attempts: u32,
+    #[envconfig(from = "WEBHOOK_BACKOFF_MS", default = "1000")]
+    webhook_backoff_ms: u64,
+    #[envconfig(from = "WEBHOOK_TIMEOUT", default = "10")]
+    webhook_timeout: u64,
+    #[envconfig(from = "WEBHOOK_ALLOWED_HOSTS")]
+    webhook_allowed_hosts: Option<String>,
+    #[envconfig(from = "CACHE_DB")]
+    cache_db: Option<String>,
+    #[envconfig(from = "CACHE_DURATION", default = "36000")]
+    cache_duration: u64,
+    #[envconfig(from = "CACHE_EMPTY_DURATION", default = "60")]
+    cache_empty_duration: u64,
+    #[envconfig(from = "CACHE_NOT_FOUND_DURATION", default = "600")]
+    cache_not_found_duration: u64,
+    #[envconfig(from = "CACHE_RATE_LIMITED_DURATION", default = "30")]
+    cache_rate_limited_duration: u64,
+    #[envconfig(from = "CACHE_ERROR_DURATION", default = "0")]
+    cache_error_duration: u64,
+    #[envconfig(nested = true)]
+    scraper: scraper::Configuration,
 }
 
 #[derive(Clone)]
 pub struct State {
     config: Configuration,
-    parsed_allowed_origins: Vec<String>,
+    cors: cors::Policy,
+    api_keys: apikey::Keys,
     result_cache: ResultCache,
+    /// Set if CSRF tokens are verified rather than only checked for presence
+    csrf: Option<csrf::Verifier>,
+    /// Pool of all scrapes of the server, `scraper::scrape` has its own and is not used
+    clients: scraper::http::ClientPool,
+    canaries: health::Canaries,
+    jobs: jobs::Jobs,
+    webhooks: webhook::Webhooks,
 }
 
-pub type ResultCache = moka::future::Cache<String, Option<scraper::ScrapeResult>>;
-
 impl State {
     fn new(config: Configuration) -> Result<Self> {
         Ok(Self {
-            parsed_allowed_origins: config
-                .allowed_origins
-                .split(',')
-                .filter(|x| !x.is_empty())
-                .map(|x| x.to_string())
-                .collect(),
+            cors: cors::Policy::new(&config)?,
+            canaries: health::Canaries::new(&config)?,
+            jobs: jobs::Jobs::new(&config),
+            webhooks: webhook::Webhooks::new(&config),
+            api_keys: apikey::Keys::new(&config)?,
+            result_cache: ResultCache::open(&config)?,
+            csrf: config.csrf_secret.as_ref().map(|secret| {
+                csrf::Verifier::new(
+                    secret,
+                    &config.csrf_salt,
+                    std::time::Duration::from_secs(config.csrf_max_age),
+                )
+            }),
             config,
-            result_cache: moka::future::CacheBuilder::new(1000)
-                .initial_capacity(1000)
-                .support_invalidation_closures()
-                .time_to_idle(std::time::Duration::from_secs(10 * 60))
-                .time_to_live(std::time::Duration::from_secs(100 * 60))
-                .build(),
+            clients: scraper::http::ClientPool::new(),
         })
     }
-    pub fn is_allowed_origin(&self, origin: Option<&str>) -> bool {
-        match origin {
-            Some(origin) => {
-                let mut allowed = false;
-                for host in &self.parsed_allowed_origins {
-                    if host == origin {
-                        allowed = true;
-                    }
-                }
-                allowed || self.parsed_allowed_origins.is_empty()
-            }
-            None => self.config.allow_empty_origin,
-        }
-    }
 }
 
 impl Default for Configuration {
@@ -94,15 +156,42 @@ impl Default for Configuration {
                 .next()
                 .unwrap(),
             allowed_origins: "".to_string(),
+            cors_allowed_methods: "GET,POST".to_string(),
+            cors_allowed_headers: "content-type,x-csrf-token".to_string(),
+            cors_max_age: 600,
             check_csrf_presence: false,
-            tumblr_api_key: std::env::var("TUMBLR_API_KEY").ok(),
-            proxy_url: None,
-            camo_host: None,
-            camo_key: None,
+            csrf_secret: None,
+            csrf_salt: "scraper csrf".to_string(),
+            csrf_max_age: 86400,
             enable_get_request: false,
-            preferred_nitter_instance_host: None,
             log_level: LevelFilter::Info,
             allow_empty_origin: false,
+            legacy_status_codes: false,
+            admin_token: None,
+            api_keys: None,
+            api_key_file: None,
+            api_key_rate_limit: 60,
+            api_keys_bypass_origin: false,
+            canary_urls: None,
+            canary_interval: 900,
+            batch_max_urls: 50,
+            batch_concurrency: 8,
+            job_max_pending: 256,
+            job_retention: 600,
+            webhook_secret: None,
+            webhook_queue_size: 1000,
+            webhook_workers: 4,
+            webhook_attempts: 5,
+            webhook_backoff_ms: 1000,
+            webhook_timeout: 10,
+            webhook_allowed_hosts: None,
+            cache_db: None,
+            cache_duration: 36000,
+            cache_empty_duration: 60,
+            cache_not_found_duration: 600,
+            cache_rate_limited_duration: 30,
+            cache_error_duration: 0,
+            scraper: scraper::Configuration::default(),
         };
         trace!("created config: {:?}", s);
         s
@@ -151,14 +240,45 @@ async fn main_start() -> Result<()> {
             .module("scraper", config.log_level)
             .build(),
     );
+    log::info!(
+        "enabled scrapers: {}",
+        scraper::REGISTRY
+            .names()
+            .filter(|x| config.scraper.is_enabled_scraper(x))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
     let state = Arc::new(State::new(config.clone())?);
+    health::spawn_canaries(state.clone());
+    if state.webhooks.is_enabled() {
+        state.webhooks.spawn_workers(state.config.webhook_workers)?;
+    }
+    let cors_state = state.clone();
+    let csrf_state = state.clone();
+    let key_state = state.clone();
     let app = axum::Router::new()
         .route("/images/scrape", get(web::scrape).post(web::scrape_post))
-        .layer(Extension(state.clone()))
+        .route("/images/scrape/batch", post(web::scrape_batch))
+        .route("/jobs", post(jobs::submit))
+        .route("/jobs/:id", get(jobs::poll))
+        .layer(axum::middleware::from_fn(move |a, b| {
+            let state = csrf_state.clone();
+            csrf::check(a, state, b)
+        }))
+        .layer(axum::middleware::from_fn(move |a, b| {
+            let state = cors_state.clone();
+            cors::check(a, state, b)
+        }))
         .layer(axum::middleware::from_fn(move |a, b| {
-            let state = state.clone();
-            web::origin_check(a, state, b)
+            let state = key_state.clone();
+            apikey::authenticate(a, state, b)
         }))
+        .merge(admin::router(state.clone()))
+        .route("/metrics", get(exporter::render))
+        .route("/openapi.json", get(openapi::document))
+        .merge(health::router())
+        .layer(Extension(state))
+        .layer(axum::middleware::from_fn(exporter::track))
         .layer(axum::middleware::from_fn(web::latency));
     axum::Server::bind(&config.bind_to)
         .serve(app.into_make_service())
@@ -0,0 +1,83 @@
+//! Prometheus metrics of the scrapers and their upstream requests
+//!
+//! All metrics are registered in the default registry, so they are part of
+//! `prometheus::gather()`.
+
+use lazy_static::lazy_static;
+use prometheus::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+
+lazy_static! {
+    /// Finished scrapes by scraper and result, which is `found`, `empty` or the error kind
+    pub(crate) static ref SCRAPES: IntCounterVec = register_int_counter_vec!(
+        "scraper_scrapes_total",
+        "Finished scrapes by scraper and result",
+        &["scraper", "result"]
+    )
+    .expect("failure in setting up metrics");
+    pub(crate) static ref SCRAPE_DURATION: HistogramVec = register_histogram_vec!(
+        "scraper_scrape_duration_seconds",
+        "Time taken by scrapes, including all upstream requests",
+        &["scraper"],
+        vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]
+    )
+    .expect("failure in setting up metrics");
+    /// Requests sent upstream by the scraper making them, [`host_label`] and status code,
+    /// `error` if there was no response
+    pub(crate) static ref UPSTREAM_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "scraper_upstream_requests_total",
+        "Requests sent upstream by scraper, host and status",
+        &["scraper", "host", "status"]
+    )
+    .expect("failure in setting up metrics");
+    pub(crate) static ref UPSTREAM_RETRIES: IntCounterVec = register_int_counter_vec!(
+        "scraper_upstream_retries_total",
+        "Requests sent upstream that were retried after a transient failure, by scraper and host",
+        &["scraper", "host"]
+    )
+    .expect("failure in setting up metrics");
+    /// Lookups in the cache of upstream responses, `hit` or `miss`
+    pub(crate) static ref RESPONSE_CACHE: IntCounterVec = register_int_counter_vec!(
+        "scraper_response_cache_requests_total",
+        "Lookups in the cache of upstream responses by result",
+        &["result"]
+    )
+    .expect("failure in setting up metrics");
+}
+
+/// Sites the scrapers talk to, requests to other hosts are counted as `other`
+const UPSTREAM_HOSTS: &[&str] = &[
+    "buzzly.art",
+    "derpibooru.org",
+    "derpicdn.net",
+    "deviantart.com",
+    "nitter.net",
+    "tumblr.com",
+    "twimg.com",
+    "twitter.com",
+    "wixmp.com",
+];
+
+/// Label of the upstream host, the known site it belongs to or `other`
+///
+/// Probes reach any host a client submits, so the hosts are not used as they are.
+pub(crate) fn host_label(host: &str) -> &'static str {
+    UPSTREAM_HOSTS
+        .iter()
+        .find(|x| host == **x || host.ends_with(&format!(".{}", x)))
+        .copied()
+        .unwrap_or("other")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_host_label() {
+        assert_eq!("twitter.com", host_label("twitter.com"));
+        assert_eq!("twimg.com", host_label("pbs.twimg.com"));
+        assert_eq!("tumblr.com", host_label("64.media.tumblr.com"));
+        assert_eq!("other", host_label("nottwitter.com"));
+        assert_eq!("other", host_label("static.manebooru.art"));
+    }
+}
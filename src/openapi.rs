@@ -0,0 +1,223 @@
+//! OpenAPI document of the scrape API, served at `/openapi.json`
+//!
+//! Schemas are derived from the request and response types, the error responses of
+//! each operation from the status each [`ErrorKind`] it may fail with is answered with.
+
+use std::collections::BTreeMap;
+
+use axum::{http, response};
+use utoipa::openapi::{self, ContentBuilder, Ref, RefOr, ResponseBuilder};
+use utoipa::{Modify, OpenApi};
+
+use scraper::{ErrorKind, ScrapeImage, ScrapeResult, ScrapeResultData, ScrapeResultError};
+
+use crate::jobs::{self, Job, JobStatus};
+use crate::web::{
+    self, json_response, status_for, BatchRequest, BatchResponse, BatchResult, ScrapeRequest,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "scraper",
+        description = "Extracts images and metadata from posts. With `LEGACY_STATUS_CODES` \
+                       enabled, errors are answered with 200 instead of the listed status."
+    ),
+    paths(
+        web::scrape_post,
+        web::scrape,
+        web::scrape_batch,
+        jobs::submit,
+        jobs::poll
+    ),
+    components(schemas(
+        ScrapeRequest,
+        ScrapeResult,
+        ScrapeResultData,
+        ScrapeImage,
+        ScrapeResultError,
+        ErrorKind,
+        BatchRequest,
+        BatchResult,
+        BatchResponse,
+        Job,
+        JobStatus
+    )),
+    modifiers(&ErrorResponses)
+)]
+pub struct ApiDoc;
+
+/// Adds the error responses to the operations
+struct ErrorResponses;
+
+fn error_response(description: String) -> RefOr<openapi::Response> {
+    ResponseBuilder::new()
+        .description(description)
+        .content(
+            "application/json",
+            ContentBuilder::new()
+                .schema(Ref::from_schema_name("ScrapeResultError"))
+                .build(),
+        )
+        .build()
+        .into()
+}
+
+/// Kinds every operation may fail with, because of the API key, origin and CSRF checks
+const COMMON: [(ErrorKind, &str); 4] = [
+    (ErrorKind::Unauthorized, "API key missing or invalid"),
+    (
+        ErrorKind::Forbidden,
+        "Origin not allowed or CSRF token missing or invalid",
+    ),
+    (
+        ErrorKind::RateLimited,
+        "Request budget of the API key exhausted",
+    ),
+    (ErrorKind::Internal, "Internal error"),
+];
+
+/// Kinds the operations at the path fail with on their own
+fn path_kinds(path: &str) -> Vec<ErrorKind> {
+    match path {
+        // everything but the kinds of the checks and of unknown jobs
+        "/images/scrape" => ErrorKind::ALL
+            .iter()
+            .copied()
+            .filter(|x| {
+                !matches!(
+                    x,
+                    ErrorKind::Unauthorized | ErrorKind::Forbidden | ErrorKind::NotFound
+                )
+            })
+            .collect(),
+        "/images/scrape/batch" => vec![ErrorKind::InvalidUrl],
+        "/jobs" => vec![ErrorKind::InvalidUrl, ErrorKind::RateLimited],
+        "/jobs/{id}" => vec![ErrorKind::NotFound],
+        _ => Vec::new(),
+    }
+}
+
+impl Modify for ErrorResponses {
+    fn modify(&self, openapi: &mut openapi::OpenApi) {
+        for (path, item) in openapi.paths.paths.iter_mut() {
+            let own = path_kinds(path);
+            let mut codes: BTreeMap<String, Vec<ErrorKind>> = BTreeMap::new();
+            for kind in own.iter().chain(COMMON.iter().map(|(kind, _)| kind)) {
+                let codes = codes
+                    .entry(status_for(*kind).as_u16().to_string())
+                    .or_default();
+                if !codes.contains(kind) {
+                    codes.push(*kind);
+                }
+            }
+            for operation in item.operations.values_mut() {
+                let responses = &mut operation.responses.responses;
+                for (status, kinds) in &codes {
+                    let listed = kinds
+                        .iter()
+                        .map(|x| format!("`{}`", x.as_str()))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    let reason = match responses.get(status) {
+                        Some(RefOr::T(response)) => Some(response.description.clone()),
+                        _ => COMMON
+                            .iter()
+                            .find(|(kind, _)| kinds.contains(kind) && !own.contains(kind))
+                            .map(|(_, reason)| reason.to_string()),
+                    };
+                    let description = match reason {
+                        Some(reason) => format!("{}, with {}", reason, listed),
+                        None => format!("Failed with {}", listed),
+                    };
+                    responses.insert(status.clone(), error_response(description));
+                }
+            }
+        }
+    }
+}
+
+pub async fn document() -> response::Response<String> {
+    json_response(http::StatusCode::OK, &ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Collects the targets of all `$ref`s in the document
+    fn refs<'a>(value: &'a serde_json::Value, found: &mut Vec<&'a str>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                found.extend(map.get("$ref").and_then(|x| x.as_str()));
+                map.values().for_each(|x| refs(x, found));
+            }
+            serde_json::Value::Array(list) => list.iter().for_each(|x| refs(x, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_document() -> anyhow::Result<()> {
+        let doc = serde_json::to_value(ApiDoc::openapi())?;
+        let mut found = Vec::new();
+        refs(&doc, &mut found);
+        for target in found {
+            let name = target.trim_start_matches("#/components/schemas/");
+            assert!(
+                doc["components"]["schemas"].get(name).is_some(),
+                "dangling {}",
+                target
+            );
+        }
+        let scrape = &doc["paths"]["/images/scrape"]["post"];
+        assert_eq!(
+            "#/components/schemas/ScrapeRequest",
+            scrape["requestBody"]["content"]["application/json"]["schema"]["$ref"]
+        );
+        assert!(scrape["responses"]["502"]["description"]
+            .as_str()
+            .unwrap()
+            .contains("`upstream_not_found`"));
+        assert_eq!(
+            "Failed with `timeout`",
+            scrape["responses"]["504"]["description"]
+        );
+        let result = doc["components"]["schemas"]["ScrapeResult"]["oneOf"]
+            .as_array()
+            .unwrap();
+        assert_eq!(3, result.len());
+        assert_eq!(
+            ErrorKind::ALL.len(),
+            doc["components"]["schemas"]["ErrorKind"]["enum"]
+                .as_array()
+                .unwrap()
+                .len()
+        );
+        let poll = &doc["paths"]["/jobs/{id}"]["get"]["responses"];
+        assert_eq!(
+            "Unknown or expired job, with `not_found`",
+            poll["404"]["description"]
+        );
+        assert_eq!(
+            "Internal error, with `internal`",
+            poll["500"]["description"]
+        );
+        assert_eq!(
+            "API key missing or invalid, with `unauthorized`",
+            poll["401"]["description"]
+        );
+        let batch = &doc["paths"]["/images/scrape/batch"]["post"]["responses"];
+        assert_eq!(
+            "Empty or too large batch, with `invalid_url`",
+            batch["400"]["description"]
+        );
+        assert!(batch["502"].is_null());
+        let submit = &doc["paths"]["/jobs"]["post"]["responses"];
+        assert_eq!(
+            "Too many pending jobs or request budget of the API key exhausted, with `rate_limited`",
+            submit["429"]["description"]
+        );
+        Ok(())
+    }
+}
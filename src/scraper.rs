@@ -1,21 +1,33 @@
+#[cfg(feature = "buzzly")]
 mod buzzly;
+#[cfg(feature = "deviantart")]
 mod deviantart;
+#[cfg(feature = "nitter")]
 mod nitter;
+#[cfg(feature = "philomena")]
 mod philomena;
+#[cfg(feature = "raw")]
 mod raw;
+#[cfg(feature = "tumblr")]
 mod tumblr;
+#[cfg(feature = "twitter")]
 mod twitter;
 
 use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::{Context, Result};
-use itertools::Itertools;
 use log::debug;
 use serde::{Deserialize, Serialize};
 #[cfg(test)]
 use visit_diff::Diff;
 
-use crate::Configuration;
+use crate::{
+    error::ErrorKind,
+    http::{Client, ClientPool, ClientSettings},
+    metrics::{SCRAPES, SCRAPE_DURATION},
+    Configuration,
+};
 
 #[cfg(not(test))]
 pub type UrlT = url::Url;
@@ -45,66 +57,80 @@ pub fn url_to_str(f: &UrlT) -> String {
     f.to_string()
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+/// Result of a scrape, `null` if the URL had no images
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default, utoipa::ToSchema)]
 #[cfg_attr(test, derive(Diff))]
 #[serde(untagged)]
 pub enum ScrapeResult {
     Err(ScrapeResultError),
     Ok(ScrapeResultData),
+    #[default]
     None,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, utoipa::ToSchema)]
 #[cfg_attr(test, derive(Diff))]
 pub struct ScrapeResultError {
-    errors: Vec<String>,
+    pub errors: Vec<String>,
+    #[serde(default)]
+    pub code: ErrorKind,
+}
+
+impl ScrapeResultError {
+    pub fn new(code: ErrorKind, error: impl Into<String>) -> Self {
+        Self {
+            errors: vec![error.into()],
+            code,
+        }
+    }
+
+    /// Lists the error chain, leaving out reqwest errors which may contain URLs with keys
+    pub fn from_error(e: &anyhow::Error) -> Self {
+        let mut errors = Vec::new();
+        debug!("request error: {}", e);
+        for e in e.chain() {
+            if !e.is::<reqwest::Error>() {
+                debug!("request error chain {}: {}", errors.len(), e);
+                errors.push(e.to_string())
+            }
+        }
+        Self {
+            errors,
+            code: ErrorKind::from_error(e),
+        }
+    }
 }
 
 impl From<String> for ScrapeResultError {
     fn from(f: String) -> Self {
-        Self { errors: vec![f] }
+        Self::new(ErrorKind::Internal, f)
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, utoipa::ToSchema)]
 #[cfg_attr(test, derive(Diff))]
 pub struct ScrapeResultData {
-    source_url: Option<UrlT>,
-    author_name: Option<String>,
-    additional_tags: Option<Vec<String>>,
-    description: Option<String>,
-    images: Vec<ScrapeImage>,
-}
-
-impl Default for ScrapeResult {
-    fn default() -> Self {
-        Self::None
-    }
+    #[schema(value_type = Option<String>, format = Uri)]
+    pub source_url: Option<UrlT>,
+    pub author_name: Option<String>,
+    pub additional_tags: Option<Vec<String>>,
+    pub description: Option<String>,
+    pub images: Vec<ScrapeImage>,
 }
 
 impl ScrapeResult {
     pub fn from_err(e: Arc<anyhow::Error>) -> ScrapeResult {
-        ScrapeResult::Err(ScrapeResultError {
-            errors: {
-                let mut errors = Vec::new();
-                debug!("request error: {}", e);
-                for e in e.chain() {
-                    if !e.is::<reqwest::Error>() {
-                        debug!("request error chain {}: {}", errors.len(), e);
-                        errors.push(e)
-                    }
-                }
-                errors.iter().map(|e| format!("{}", e)).collect()
-            },
-        })
+        ScrapeResult::Err(ScrapeResultError::from_error(&e))
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, utoipa::ToSchema)]
 #[cfg_attr(test, derive(Diff))]
 pub struct ScrapeImage {
-    url: UrlT,
-    camo_url: UrlT,
+    #[schema(value_type = String, format = Uri)]
+    pub url: UrlT,
+    #[schema(value_type = String, format = Uri)]
+    pub camo_url: UrlT,
 }
 
 impl PartialEq for ScrapeImage {
@@ -114,150 +140,528 @@ impl PartialEq for ScrapeImage {
     }
 }
 
-pub fn client(config: &Configuration) -> Result<reqwest::Client> {
-    client_with_redir_limit(config, reqwest::redirect::Policy::none())
+/// A site specific scraper that can be added to a [`Registry`].
+///
+/// URLs are matched in two phases: first every scraper gets to look at the URL via
+/// [`SiteScraper::matches`] without touching the network, only if none of them claims
+/// it are the network bound [`SiteScraper::probe`]s run. Within a phase, the matching
+/// scraper with the lowest [`SiteScraper::priority`] is used.
+#[async_trait::async_trait]
+pub trait SiteScraper: Send + Sync {
+    /// Name of the scraper, used for logging and error context
+    fn name(&self) -> &'static str;
+
+    /// Lower values take precedence over higher ones
+    fn priority(&self) -> i32;
+
+    /// Settings of the pooled client passed to [`SiteScraper::probe`] and [`SiteScraper::scrape`]
+    fn client_settings(&self, config: &Configuration) -> ClientSettings {
+        ClientSettings::new(config)
+    }
+
+    /// Rewrites URLs of this site into their canonical form, must not do any I/O
+    ///
+    /// Called for URLs before dispatch, the rewritten URL is only used if
+    /// [`SiteScraper::matches`] accepts it.
+    fn canonicalize(&self, _url: &mut url::Url) {}
+
+    /// Returns true if this scraper can handle the given URL, must not do any I/O
+    fn matches(&self, url: &url::Url) -> bool;
+
+    /// Returns true if this scraper can handle the given URL after asking the network,
+    /// ie. by resolving the host or making a HEAD request
+    async fn probe(
+        &self,
+        _config: &Configuration,
+        _client: &Client,
+        _url: &url::Url,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Returns true if a successful [`SiteScraper::probe`] holds for every URL of the
+    /// host, ie. because it only looks at the host, so further URLs of the host are
+    /// dispatched without probing
+    fn probe_claims_host(&self) -> bool {
+        false
+    }
+
+    async fn scrape(
+        &self,
+        config: &Configuration,
+        client: &Client,
+        url: &url::Url,
+    ) -> Result<Option<ScrapeResult>>;
 }
 
-pub fn client_with_redir_limit(
-    config: &Configuration,
-    redir_policy: reqwest::redirect::Policy,
-) -> Result<reqwest::Client> {
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_millis(5000))
-        .connect_timeout(std::time::Duration::from_millis(2500))
-        .user_agent("curl/7.83.1")
-        .cookie_store(true)
-        .redirect(redir_policy);
-    let client = match config.proxy_url.clone() {
-        None => client,
-        Some(proxy_url) => {
-            use reqwest::Proxy;
-            use std::str::FromStr;
-            let proxy_url = url::Url::from_str(&proxy_url)?;
-            let proxy = match proxy_url.scheme() {
-                "http" => Proxy::all(proxy_url)?,
-                "https" => Proxy::all(proxy_url)?,
-                "socks" => Proxy::all(proxy_url)?,
-                "socks5" => Proxy::all(proxy_url)?,
-                _ => anyhow::bail!(
-                    "unknown client proxy protocol, specify http, https, socks or socks5"
-                ),
-            };
-            client.proxy(proxy)
-        }
-    };
-    Ok(client.build()?)
+/// Set of scrapers a URL is dispatched to.
+///
+/// Hosts claimed by a probe are remembered for `CACHE_CHECK_DURATION`, further URLs on
+/// the same host go to that scraper without probing again. Only probes which hold for
+/// the whole host claim it, see [`SiteScraper::probe_claims_host`].
+pub struct Registry {
+    scrapers: Vec<Box<dyn SiteScraper>>,
+    /// Host to name of the scraper whose probe claimed it and when
+    hosts: moka::sync::Cache<String, (&'static str, Instant)>,
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-enum Scraper {
-    Twitter,
-    Nitter,
-    Tumblr,
-    DeviantArt,
-    Philomena,
-    Buzzly,
-    Raw,
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            scrapers: Vec::new(),
+            hosts: moka::sync::Cache::new(10_000),
+        }
+    }
 }
 
-impl Scraper {
-    async fn get_scraper(config: &Configuration, url: &url::Url) -> Result<Option<Self>> {
-        let (r0, r1, r2, r3, r4, r5, r6) = tokio::try_join!(
-            async {
-                twitter::is_twitter(url)
-                    .await
-                    .map(|mat| if mat { Some(Self::Twitter) } else { None })
-            },
-            async {
-                nitter::is_nitter(url)
-                    .await
-                    .map(|mat| if mat { Some(Self::Nitter) } else { None })
-            },
-            async {
-                tumblr::is_tumblr(url)
-                    .await
-                    .map(|mat| if mat { Some(Self::Tumblr) } else { None })
-            },
-            async {
-                deviantart::is_deviantart(url).await.map(|mat| {
-                    if mat {
-                        Some(Self::DeviantArt)
-                    } else {
-                        None
-                    }
-                })
-            },
-            async {
-                philomena::is_philomena(url).await.map(|mat| {
-                    if mat {
-                        Some(Self::Philomena)
-                    } else {
-                        None
-                    }
-                })
-            },
-            async {
-                buzzly::is_buzzlyart(url)
-                    .await
-                    .map(|mat| if mat { Some(Self::Buzzly) } else { None })
-            },
-            async {
-                raw::is_raw(url, config)
-                    .await
-                    .map(|mat| if mat { Some(Self::Raw) } else { None })
-            },
-        )?;
-        let res = vec![r0, r1, r2, r3, r4, r5, r6];
-        let res: Vec<Scraper> = res.into_iter().flatten().collect_vec();
-        Ok(if res.is_empty() {
-            None
-        } else if res.len() == 1 {
-            Some(res[0])
-        } else if res.len() > 1 {
-            let mut res = res;
-            res.sort();
-            Some(res[0])
-        } else {
-            unreachable!("res must be empty but is {:?}", res);
-        })
+impl Registry {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a registry containing all scrapers enabled via cargo features
+    pub fn builtin() -> Self {
+        #[allow(unused_mut)]
+        let mut registry = Self::new();
+        #[cfg(feature = "twitter")]
+        twitter::register(&mut registry);
+        #[cfg(feature = "nitter")]
+        nitter::register(&mut registry);
+        #[cfg(feature = "tumblr")]
+        tumblr::register(&mut registry);
+        #[cfg(feature = "deviantart")]
+        deviantart::register(&mut registry);
+        #[cfg(feature = "philomena")]
+        philomena::register(&mut registry);
+        #[cfg(feature = "buzzly")]
+        buzzly::register(&mut registry);
+        #[cfg(feature = "raw")]
+        raw::register(&mut registry);
+        registry
+    }
+
+    pub fn register<S: SiteScraper + 'static>(&mut self, scraper: S) {
+        debug!(
+            "registering scraper {} with priority {}",
+            scraper.name(),
+            scraper.priority()
+        );
+        self.scrapers.push(Box::new(scraper));
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.scrapers.iter().map(|x| x.name())
+    }
+
+    fn enabled<'a: 'c, 'c>(
+        &'a self,
+        config: &'c Configuration,
+    ) -> impl Iterator<Item = &'a dyn SiteScraper> + 'c {
+        self.scrapers
+            .iter()
+            .map(|x| x.as_ref())
+            .filter(move |x| config.is_enabled_scraper(x.name()))
     }
 
-    async fn execute_scrape(
-        self,
+    /// Scraper a probe recently matched for the host
+    fn classified(&self, config: &Configuration, host: &str) -> Option<&dyn SiteScraper> {
+        let host = host.to_string();
+        let (name, since) = self.hosts.get(&host)?;
+        if since.elapsed() >= config.check_cache_duration() {
+            self.hosts.invalidate(&host);
+            return None;
+        }
+        self.enabled(config).find(|x| x.name() == name)
+    }
+
+    async fn get_scraper(
+        &self,
         config: &Configuration,
+        clients: &ClientPool,
         url: &url::Url,
+    ) -> Result<Option<&dyn SiteScraper>> {
+        let offline = self
+            .enabled(config)
+            .filter(|x| x.matches(url))
+            .min_by_key(|x| x.priority());
+        if offline.is_some() {
+            return Ok(offline);
+        }
+        let host = url.host_str().map(|x| x.to_ascii_lowercase());
+        if let Some(scraper) = host.as_ref().and_then(|x| self.classified(config, x)) {
+            debug!("host of {} is known to be {}", url, scraper.name());
+            return Ok(Some(scraper));
+        }
+        debug!("no offline match for {}, probing", url);
+        let scrapers: Vec<&dyn SiteScraper> = self.enabled(config).collect();
+        let probes = futures_util::future::join_all(scrapers.iter().map(|x| async move {
+            let client = clients
+                .get(&x.client_settings(config))?
+                .for_scraper(x.name());
+            x.probe(config, &client, url).await
+        }))
+        .await;
+        let mut first_err = None;
+        let mut matched = Vec::new();
+        for (scraper, probe) in scrapers.into_iter().zip(probes) {
+            match probe {
+                Ok(true) => matched.push(scraper),
+                Ok(false) => (),
+                Err(e) => {
+                    debug!("probe of {} failed: {:?}", scraper.name(), e);
+                    first_err.get_or_insert(e);
+                }
+            }
+        }
+        // a failing probe only fails the scrape if no other scraper wants the URL
+        match (matched.into_iter().min_by_key(|x| x.priority()), first_err) {
+            (Some(scraper), _) => {
+                if let Some(host) = host.filter(|_| scraper.probe_claims_host()) {
+                    self.hosts.insert(host, (scraper.name(), Instant::now()));
+                }
+                Ok(Some(scraper))
+            }
+            (None, Some(e)) => Err(e),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Canonical form of the URL, which is what gets dispatched, cached and returned as
+    /// source.
+    ///
+    /// The fragment is always dropped. The enabled scrapers are then tried in order of
+    /// priority: the first one matching the URL once tracking parameters and trailing
+    /// slashes are removed and its own rules applied rewrites it. URLs no scraper
+    /// matches offline are left as they are otherwise.
+    pub fn canonicalize(&self, config: &Configuration, url: &str) -> Result<url::Url> {
+        use std::str::FromStr;
+        let mut url = url::Url::from_str(url).context("could not parse URL for scraper")?;
+        url.set_fragment(None);
+        let mut scrapers: Vec<&dyn SiteScraper> = self.enabled(config).collect();
+        scrapers.sort_by_key(|x| x.priority());
+        for scraper in scrapers {
+            let mut candidate = url.clone();
+            strip_tracking(&mut candidate);
+            scraper.canonicalize(&mut candidate);
+            if scraper.matches(&candidate) {
+                return Ok(candidate);
+            }
+        }
+        Ok(url)
+    }
+
+    /// Name of the scraper a scrape of the URL would be dispatched to, None if no
+    /// scraper accepts it
+    pub async fn scraper_name(
+        &self,
+        config: &Configuration,
+        clients: &ClientPool,
+        url: &str,
+    ) -> Result<Option<&'static str>> {
+        let url = self.canonicalize(config, url)?;
+        let scraper = self.get_scraper(config, clients, &url).await?;
+        Ok(scraper.map(|x| x.name()))
+    }
+
+    pub async fn scrape(
+        &self,
+        config: &Configuration,
+        clients: &ClientPool,
+        url: &str,
     ) -> Result<Option<ScrapeResult>> {
-        match self {
-            Scraper::Twitter => Ok(twitter::twitter_scrape(config, url)
-                .await
-                .context("Twitter parser failed")?),
-            Scraper::Nitter => Ok(nitter::nitter_scrape(config, url)
-                .await
-                .context("Nitter parser failed")?),
-            Scraper::Tumblr => Ok(tumblr::tumblr_scrape(config, url)
-                .await
-                .context("Tumblr parser failed")?),
-            Scraper::DeviantArt => Ok(deviantart::deviantart_scrape(config, url)
-                .await
-                .context("DeviantArt parser failed")?),
-            Scraper::Philomena => Ok(philomena::philomena_scrape(config, url)
-                .await
-                .context("Philomena parser failed")?),
-            Scraper::Buzzly => Ok(buzzly::buzzlyart_scrape(config, url)
-                .await
-                .context("Buzzly parser failed")?),
-            Scraper::Raw => Ok(raw::raw_scrape(config, url)
-                .await
-                .context("Raw parser failed")?),
+        let url = self.canonicalize(config, url)?;
+        match self.get_scraper(config, clients, &url).await? {
+            Some(scraper) => {
+                debug!("using scraper {} for {}", scraper.name(), url);
+                let client = clients
+                    .get(&scraper.client_settings(config))?
+                    .for_scraper(scraper.name());
+                let timer = SCRAPE_DURATION
+                    .with_label_values(&[scraper.name()])
+                    .start_timer();
+                let res = scraper
+                    .scrape(config, &client, &url)
+                    .await
+                    .with_context(|| format!("{} parser failed", scraper.name()));
+                timer.observe_duration();
+                let result = match &res {
+                    Ok(Some(ScrapeResult::Ok(_))) => "found",
+                    Ok(Some(ScrapeResult::Err(e))) => e.code.as_str(),
+                    Ok(Some(ScrapeResult::None)) | Ok(None) => "empty",
+                    Err(e) => ErrorKind::from_error(e).as_str(),
+                };
+                SCRAPES.with_label_values(&[scraper.name(), result]).inc();
+                res
+            }
+            None => Ok(None),
         }
     }
 }
 
+/// Query parameters only used to track who shared a link
+const TRACKING_PARAMS: &[&str] = &["fbclid", "gclid", "igshid", "mc_cid", "mc_eid", "ref_src"];
+
+/// Removes tracking parameters and trailing slashes of the path
+fn strip_tracking(url: &mut url::Url) {
+    retain_query(url, |key| {
+        !key.starts_with("utm_") && !TRACKING_PARAMS.contains(&key)
+    });
+    if url.path().len() > 1 && url.path().ends_with('/') {
+        let path = url.path().trim_end_matches('/').to_string();
+        url.set_path(&path);
+    }
+}
+
+/// Removes all query parameters whose key is rejected, and the query if none are left
+pub fn retain_query(url: &mut url::Url, keep: impl Fn(&str) -> bool) {
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| keep(key))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    if pairs.is_empty() {
+        url.set_query(None);
+    } else if url.query_pairs().count() != pairs.len() {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+}
+
+lazy_static::lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::builtin();
+    /// Clients of [`scrape`], lives as long as the process
+    static ref CLIENTS: ClientPool = ClientPool::new();
+}
+
+/// Scrapes the URL with the builtin scrapers and a process wide client pool
+///
+/// The pool is private to this function and independent of any [`ClientPool`] passed
+/// to [`Registry::scrape`], so their clients, connections and response caches are not
+/// shared. Applications managing their own pool should call
+/// `REGISTRY.scrape(config, &pool, url)` instead of mixing both.
 pub async fn scrape(config: &Configuration, url: &str) -> Result<Option<ScrapeResult>> {
+    REGISTRY.scrape(config, &CLIENTS, url).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
     use std::str::FromStr;
-    let url = url::Url::from_str(url).context("could not parse URL for scraper")?;
-    match Scraper::get_scraper(config, &url).await? {
-        Some(scraper) => scraper.execute_scrape(config, &url).await,
-        None => Ok(None),
+
+    struct Fixed(&'static str, i32, bool, bool, bool);
+
+    #[async_trait::async_trait]
+    impl SiteScraper for Fixed {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn priority(&self) -> i32 {
+            self.1
+        }
+
+        fn matches(&self, _url: &url::Url) -> bool {
+            self.2
+        }
+
+        async fn probe(
+            &self,
+            _config: &Configuration,
+            _client: &Client,
+            _url: &url::Url,
+        ) -> Result<bool> {
+            if self.3 {
+                Ok(true)
+            } else {
+                anyhow::bail!("probe of {} should not run", self.0)
+            }
+        }
+
+        fn probe_claims_host(&self) -> bool {
+            self.4
+        }
+
+        async fn scrape(
+            &self,
+            _config: &Configuration,
+            _client: &Client,
+            _url: &url::Url,
+        ) -> Result<Option<ScrapeResult>> {
+            Ok(Some(ScrapeResult::Err(self.0.to_string().into())))
+        }
+    }
+
+    #[test]
+    fn test_registry_priority() -> Result<()> {
+        let mut registry = Registry::new();
+        registry.register(Fixed("late", 20, true, false, false));
+        registry.register(Fixed("unmatched", 0, false, false, false));
+        registry.register(Fixed("early", 10, true, false, false));
+        let config = Configuration::default();
+        let url = url::Url::from_str("https://example.com/image.png")?;
+        let scraper =
+            tokio_test::block_on(registry.get_scraper(&config, &ClientPool::new(), &url))?;
+        assert_eq!(Some("early"), scraper.map(|x| x.name()));
+        let config = Configuration {
+            disabled_scrapers: "early".to_string(),
+            ..Configuration::default()
+        };
+        let scraper =
+            tokio_test::block_on(registry.get_scraper(&config, &ClientPool::new(), &url))?;
+        assert_eq!(Some("late"), scraper.map(|x| x.name()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_registry_probes_only_without_offline_match() -> Result<()> {
+        let config = Configuration::default();
+        let url = url::Url::from_str("https://example.com/image.png")?;
+        let mut registry = Registry::new();
+        registry.register(Fixed("probe", 0, false, true, true));
+        let scraper =
+            tokio_test::block_on(registry.get_scraper(&config, &ClientPool::new(), &url))?;
+        assert_eq!(Some("probe"), scraper.map(|x| x.name()));
+        registry.register(Fixed("offline", 10, true, false, false));
+        let scraper =
+            tokio_test::block_on(registry.get_scraper(&config, &ClientPool::new(), &url))?;
+        assert_eq!(Some("offline"), scraper.map(|x| x.name()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scrape_metrics() -> Result<()> {
+        let mut registry = Registry::new();
+        registry.register(Fixed("metered", 0, true, false, false));
+        let config = Configuration::default();
+        let res = tokio_test::block_on(registry.scrape(
+            &config,
+            &ClientPool::new(),
+            "https://example.com/image.png",
+        ))?;
+        assert!(matches!(res, Some(ScrapeResult::Err(_))));
+        assert_eq!(1, SCRAPES.with_label_values(&["metered", "internal"]).get());
+        assert_eq!(
+            1,
+            SCRAPE_DURATION
+                .with_label_values(&["metered"])
+                .get_sample_count()
+        );
+        Ok(())
+    }
+
+    struct Lowercase;
+
+    #[async_trait::async_trait]
+    impl SiteScraper for Lowercase {
+        fn name(&self) -> &'static str {
+            "lowercase"
+        }
+
+        fn priority(&self) -> i32 {
+            0
+        }
+
+        fn canonicalize(&self, url: &mut url::Url) {
+            let path = url.path().to_lowercase();
+            url.set_path(&path);
+        }
+
+        fn matches(&self, url: &url::Url) -> bool {
+            url.host_str() == Some("example.com")
+        }
+
+        async fn scrape(
+            &self,
+            _config: &Configuration,
+            _client: &Client,
+            _url: &url::Url,
+        ) -> Result<Option<ScrapeResult>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_canonicalize() -> Result<()> {
+        let config = Configuration::default();
+        let mut registry = Registry::new();
+        let canonical = |registry: &Registry, url| -> Result<String> {
+            Ok(registry.canonicalize(&config, url)?.to_string())
+        };
+        assert_eq!(
+            "https://example.com/a/B/?utm_medium=share",
+            canonical(&registry, "https://EXAMPLE.com/a/B/?utm_medium=share#top")?
+        );
+        registry.register(Lowercase);
+        assert_eq!(
+            "https://example.com/a/image.png?size=large",
+            canonical(
+                &registry,
+                "https://example.com/a/image.png?utm_source=x&size=large&fbclid=1#top"
+            )?
+        );
+        assert_eq!(
+            "https://example.com/a/b",
+            canonical(&registry, "https://EXAMPLE.com/a/B/?utm_medium=share")?
+        );
+        assert_eq!(
+            "https://example.com/",
+            canonical(&registry, "https://example.com/")?
+        );
+        assert_eq!(
+            "https://cdn.example.com/a/B/?utm_source=x",
+            canonical(&registry, "https://cdn.example.com/a/B/?utm_source=x#top")?
+        );
+        let config = Configuration {
+            disabled_scrapers: "lowercase".to_string(),
+            ..Configuration::default()
+        };
+        assert_eq!(
+            "https://example.com/a/B/",
+            registry
+                .canonicalize(&config, "https://example.com/a/B/")?
+                .to_string()
+        );
+        assert!(registry.canonicalize(&config, "not a url").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_registry_remembers_probed_hosts() -> Result<()> {
+        let config = Configuration::default();
+        let url = url::Url::from_str("https://example.com/image.png")?;
+        let other = url::Url::from_str("https://example.com/other.png")?;
+        let mut registry = Registry::new();
+        registry.register(Fixed("probe", 10, false, true, true));
+        let scraper =
+            tokio_test::block_on(registry.get_scraper(&config, &ClientPool::new(), &url))?;
+        assert_eq!(Some("probe"), scraper.map(|x| x.name()));
+        registry.register(Fixed("better", 0, false, true, true));
+        let scraper =
+            tokio_test::block_on(registry.get_scraper(&config, &ClientPool::new(), &other))?;
+        assert_eq!(Some("probe"), scraper.map(|x| x.name()));
+        let config = Configuration {
+            check_cache_duration: 0,
+            ..Configuration::default()
+        };
+        let scraper =
+            tokio_test::block_on(registry.get_scraper(&config, &ClientPool::new(), &other))?;
+        assert_eq!(Some("better"), scraper.map(|x| x.name()));
+        Ok(())
+    }
+    #[test]
+    fn test_registry_probes_each_url_of_unclaimed_hosts() -> Result<()> {
+        let config = Configuration::default();
+        let url = url::Url::from_str("https://example.com/image.png")?;
+        let other = url::Url::from_str("https://example.com/page.html")?;
+        let mut registry = Registry::new();
+        registry.register(Fixed("per_url", 10, false, true, false));
+        let scraper =
+            tokio_test::block_on(registry.get_scraper(&config, &ClientPool::new(), &url))?;
+        assert_eq!(Some("per_url"), scraper.map(|x| x.name()));
+        registry.register(Fixed("better", 0, false, true, false));
+        let scraper =
+            tokio_test::block_on(registry.get_scraper(&config, &ClientPool::new(), &other))?;
+        assert_eq!(Some("better"), scraper.map(|x| x.name()));
+        Ok(())
     }
 }
@@ -1,14 +1,16 @@
-use std::collections::HashMap;
 use std::str::FromStr;
 
 use anyhow::Result;
 use graphql_client::{GraphQLQuery, Response};
 use log::*;
 use regex::Regex;
-use reqwest::{Client, Url};
+use reqwest::Url;
 
 use crate::camo::camo_url;
+use crate::error::ScrapeError;
+use crate::http::Client;
 use crate::scraper::{from_url, ScrapeImage, ScrapeResult, ScrapeResultData};
+use crate::scraper::{Registry, SiteScraper};
 use crate::Configuration;
 
 lazy_static::lazy_static! {
@@ -23,13 +25,43 @@ lazy_static::lazy_static! {
 )]
 pub struct GetSubmission;
 
-pub async fn is_buzzlyart(url: &Url) -> Result<bool> {
+pub struct Buzzly;
+
+#[async_trait::async_trait]
+impl SiteScraper for Buzzly {
+    fn name(&self) -> &'static str {
+        "Buzzly"
+    }
+
+    fn priority(&self) -> i32 {
+        60
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        is_buzzlyart(url)
+    }
+
+    async fn scrape(
+        &self,
+        config: &Configuration,
+        client: &Client,
+        url: &Url,
+    ) -> Result<Option<ScrapeResult>> {
+        buzzlyart_scrape(config, client, url).await
+    }
+}
+
+pub fn register(registry: &mut Registry) {
+    registry.register(Buzzly);
+}
+
+pub fn is_buzzlyart(url: &Url) -> bool {
     trace!("buzzly on {:?}?", url.as_str());
     if URL_REGEX.is_match_at(url.as_str(), 0) {
         trace!("buzzly matched on URL");
-        return Ok(true);
+        return true;
     }
-    Ok(false)
+    false
 }
 
 pub async fn make_buzzly_doc_request(
@@ -37,13 +69,6 @@ pub async fn make_buzzly_doc_request(
     slug: &str,
     username: &str,
 ) -> Result<get_submission::ResponseData> {
-    #[derive(serde::Serialize)]
-    struct Query {
-        #[serde(rename = "operationName")]
-        operation_name: String,
-        query: String,
-        variables: HashMap<String, String>,
-    }
     let vars = get_submission::Variables {
         slug: slug.to_string(),
         username: username.to_string(),
@@ -60,42 +85,46 @@ pub async fn make_buzzly_doc_request(
         .error_for_status()?
         .json()
         .await?;
-    Ok(r.data.expect("missing response data"))
+    r.data
+        .ok_or_else(|| ScrapeError::parse_failed("missing response data").into())
 }
 
-pub async fn buzzlyart_scrape(config: &Configuration, url: &Url) -> Result<Option<ScrapeResult>> {
+pub async fn buzzlyart_scrape(
+    config: &Configuration,
+    client: &Client,
+    url: &Url,
+) -> Result<Option<ScrapeResult>> {
     trace!("loading buzzly");
     let origin_url = url;
-    let client = crate::scraper::client(config)?;
     let matches = URL_REGEX.captures(url.as_str()).unwrap();
     let author_name = matches.get(1).unwrap().as_str();
     let slug = matches.get(2).unwrap().as_str();
     let data: get_submission::ResponseData =
-        make_buzzly_doc_request(&client, slug, author_name).await?;
+        make_buzzly_doc_request(client, slug, author_name).await?;
     let data = data
         .fetch_submission_by_username_and_slug
-        .ok_or_else(|| anyhow::format_err!("missing data in response"))?;
+        .ok_or_else(|| ScrapeError::parse_failed("missing data in response"))?;
     let submission = data
         .submission
-        .ok_or_else(|| anyhow::format_err!("missing submission metadata"))?;
+        .ok_or_else(|| ScrapeError::parse_failed("missing submission metadata"))?;
     let account = submission
         .account
         .as_ref()
-        .ok_or_else(|| anyhow::format_err!("missing account metadata"))?;
+        .ok_or_else(|| ScrapeError::parse_failed("missing account metadata"))?;
     trace!("got data: {account:?} {submission:?}");
     let author_name = account.username.clone();
     let description = submission.description;
     let url = submission
         .path
-        .ok_or_else(|| anyhow::format_err!("missing image path"))?;
+        .ok_or_else(|| ScrapeError::parse_failed("missing image path"))?;
     let camod_url = submission
         .thumbnail_path
-        .ok_or_else(|| anyhow::format_err!("missing image path"))?;
+        .ok_or_else(|| ScrapeError::parse_failed("missing image path"))?;
     let url: url::Url = Url::from_str(&format!("https://submissions.buzzly.art{url}"))?;
     let camod_url = Url::from_str(&format!("https://submissions.buzzly.art{camod_url}"))?;
     let tags: Vec<Option<String>> = submission
         .tags
-        .ok_or_else(|| anyhow::format_err!("missing tags fields"))?;
+        .ok_or_else(|| ScrapeError::parse_failed("missing tags fields"))?;
     let mut tags: Vec<String> = tags.into_iter().flatten().collect();
     tags.push(format!("artist:{author_name}"));
     Ok(Some(ScrapeResult::Ok(ScrapeResultData {
@@ -121,7 +150,7 @@ mod test {
     fn test_buzzlyart_scraper() -> Result<()> {
         crate::LOGGER.lock().unwrap().flush();
         let url = r#"https://buzzly.art/~mothnmag/art/fizzy"#;
-        let config = Configuration::default();
+        let config = crate::http::fixture("buzzly").build();
         let scrape = tokio_test::block_on(scrape(&config, url))?.unwrap();
 
         visit_diff::assert_eq_diff!(ScrapeResult::Ok(ScrapeResultData{
@@ -1,5 +1,7 @@
-use crate::scraper::client;
+use crate::error::ScrapeError;
+use crate::http::Client;
 use crate::scraper::ScrapeResultData;
+use crate::scraper::{Registry, SiteScraper};
 use crate::{
     scraper::{from_url, ScrapeImage, ScrapeResult},
     Configuration,
@@ -21,16 +23,49 @@ lazy_static::lazy_static! {
     static ref JPG_REGEX: Regex = Regex::from_str(r#"(https://[0-9a-z\-\.]+(?:/intermediary)?/f/[0-9a-f\-]+/[0-9a-z\-]+\.jpg/v1/fill/w_[0-9]+,h_[0-9]+,q_)([0-9]+)(,[a-z]+\\/[a-z0-6_\-]+\.jpe?g.*)"#).expect("failure in setting up essential regex");
 }
 
-pub async fn is_deviantart(url: &Url) -> Result<bool> {
+pub struct DeviantArt;
+
+#[async_trait::async_trait]
+impl SiteScraper for DeviantArt {
+    fn name(&self) -> &'static str {
+        "DeviantArt"
+    }
+
+    fn priority(&self) -> i32 {
+        40
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        is_deviantart(url)
+    }
+
+    async fn scrape(
+        &self,
+        config: &Configuration,
+        client: &Client,
+        url: &Url,
+    ) -> Result<Option<ScrapeResult>> {
+        deviantart_scrape(config, client, url).await
+    }
+}
+
+pub fn register(registry: &mut Registry) {
+    registry.register(DeviantArt);
+}
+
+pub fn is_deviantart(url: &Url) -> bool {
     match url.host_str() {
-        Some(url) => Ok(url.ends_with(".deviantart.com") || url == "deviantart.com"),
-        None => Ok(false),
+        Some(url) => url.ends_with(".deviantart.com") || url == "deviantart.com",
+        None => false,
     }
 }
 
 //TODO: cache results
-pub async fn deviantart_scrape(config: &Configuration, url: &Url) -> Result<Option<ScrapeResult>> {
-    let client = crate::scraper::client(config)?;
+pub async fn deviantart_scrape(
+    config: &Configuration,
+    client: &Client,
+    url: &Url,
+) -> Result<Option<ScrapeResult>> {
     let resp = client
         .get(url.to_owned())
         .send()
@@ -46,14 +81,14 @@ pub async fn deviantart_scrape(config: &Configuration, url: &Url) -> Result<Opti
         Some((extract_data, camo)) => match extract_data {
             ScrapeResult::Ok(mut v) => {
                 let images = try_new_hires(v.images).await?;
-                let images = try_intermediary_hires(config, images).await?;
+                let images = try_intermediary_hires(client, images).await?;
                 let source_url = match &v.source_url {
                     Some(v) => v,
-                    None => anyhow::bail!("had no source url"),
+                    None => anyhow::bail!(ScrapeError::parse_failed("had no source url")),
                 };
                 let source_url = Url::parse(&crate::scraper::url_to_str(source_url))
                     .context("source URL is not valid URL")?;
-                let images = try_old_hires(config, source_url, images, &camo)
+                let images = try_old_hires(client, source_url, images, &camo)
                     .await
                     .context("old_hires conversion failed")?;
 
@@ -70,17 +105,17 @@ pub async fn deviantart_scrape(config: &Configuration, url: &Url) -> Result<Opti
 async fn extract_data(config: &Configuration, body: &str) -> Result<Option<(ScrapeResult, Url)>> {
     let image = &IMAGE_REGEX.captures(body);
     let image = match image {
-        None => anyhow::bail!("no image found"),
+        None => anyhow::bail!(ScrapeError::parse_failed("no image found")),
         Some(image) => &image[1],
     };
     let source = &SOURCE_REGEX.captures(body);
     let source = match source {
-        None => anyhow::bail!("no source found"),
+        None => anyhow::bail!(ScrapeError::parse_failed("no source found")),
         Some(source) => &source[1],
     };
     let artist = &ARTIST_REGEX.captures(source);
     let artist = match artist {
-        None => anyhow::bail!("no artist found"),
+        None => anyhow::bail!(ScrapeError::parse_failed("no artist found")),
         Some(artist) => &artist[1],
     };
     trace!("deviant capture: {} {} {}", image, source, artist);
@@ -113,7 +148,7 @@ async fn extract_data(config: &Configuration, body: &str) -> Result<Option<(Scra
 }
 
 async fn try_intermediary_hires(
-    config: &Configuration,
+    client: &Client,
     mut images: Vec<ScrapeImage>,
 ) -> Result<Vec<ScrapeImage>> {
     for image in images.clone() {
@@ -139,7 +174,6 @@ async fn try_intermediary_hires(
             object_name = object_name
         );
         let built_url = Url::from_str(&built_url)?;
-        let client = client(config)?;
         if client
             .head(built_url.clone())
             .send()
@@ -186,14 +220,14 @@ async fn try_new_hires(mut images: Vec<ScrapeImage>) -> Result<Vec<ScrapeImage>>
 }
 
 async fn try_old_hires(
-    config: &Configuration,
+    client: &Client,
     source_url: Url,
     mut images: Vec<ScrapeImage>,
     camo: &Url,
 ) -> Result<Vec<ScrapeImage>> {
     let serial = &SERIAL_REGEX.captures(source_url.as_str());
     let serial = match serial {
-        None => anyhow::bail!("no serial captured"),
+        None => anyhow::bail!(ScrapeError::parse_failed("no serial captured")),
         Some(serial) => &serial[1],
     };
     let base36 = radix_fmt::radix(
@@ -210,8 +244,6 @@ async fn try_old_hires(
         base36 = base36
     );
 
-    let client = crate::scraper::client_with_redir_limit(config, reqwest::redirect::Policy::none())
-        .context("could not create DA scraping agent")?;
     let resp = client
         .get(built_url)
         .send()
@@ -245,12 +277,9 @@ mod test {
     fn test_deviantart_scraper() -> Result<()> {
         crate::LOGGER.lock().unwrap().flush();
         let url = r#"https://www.deviantart.com/the-park/art/Comm-Baseball-cap-derpy-833396912"#;
-        let config = Configuration::default();
+        let config = crate::http::fixture("deviantart").build();
         let scrape = tokio_test::block_on(scrape(&config, url));
-        let scrape = match scrape {
-            Ok(s) => s,
-            Err(e) => return Err(e),
-        };
+        let scrape = scrape?;
         let mut scrape = match scrape {
             Some(s) => s,
             None => anyhow::bail!("got none response from scraper"),
@@ -262,11 +291,11 @@ mod test {
                     let fixup = url_to_str(&image.url);
                     let mut fixup = url::Url::from_str(&fixup)?;
                     fixup.query_pairs_mut().clear();
-                    (*(image)).url = from_url(fixup);
+                    image.url = from_url(fixup);
                     let fixup = url_to_str(&image.camo_url);
                     let mut fixup = url::Url::from_str(&fixup)?;
                     fixup.query_pairs_mut().clear();
-                    (*(image)).camo_url = from_url(fixup);
+                    image.camo_url = from_url(fixup);
                 }
             }
         }
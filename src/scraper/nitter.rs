@@ -1,3 +1,5 @@
+use crate::http::Client;
+use crate::scraper::{Registry, SiteScraper};
 use crate::scraper::{ScrapeResult, ScrapeResultData};
 use crate::{scraper::ScrapeImage, Configuration};
 use anyhow::Context;
@@ -42,22 +44,55 @@ lazy_static! {
         .expect("failure in setting up essential regex");
 }
 
-pub async fn is_nitter(url: &Url) -> Result<bool> {
-    Ok(match url.host_str() {
+pub struct Nitter;
+
+#[async_trait::async_trait]
+impl SiteScraper for Nitter {
+    fn name(&self) -> &'static str {
+        "Nitter"
+    }
+
+    fn priority(&self) -> i32 {
+        20
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        is_nitter(url)
+    }
+
+    async fn scrape(
+        &self,
+        config: &Configuration,
+        client: &Client,
+        url: &Url,
+    ) -> Result<Option<ScrapeResult>> {
+        nitter_scrape(config, client, url).await
+    }
+}
+
+pub fn register(registry: &mut Registry) {
+    registry.register(Nitter);
+}
+
+pub fn is_nitter(url: &Url) -> bool {
+    match url.host_str() {
         None => false,
         Some(host) => {
             NITTER_INSTANCES.contains(&host.to_string()) && TWEET_REGEX.is_match(url.path())
         }
-    })
+    }
 }
-pub async fn nitter_scrape(config: &Configuration, url: &Url) -> Result<Option<ScrapeResult>> {
+pub async fn nitter_scrape(
+    config: &Configuration,
+    client: &Client,
+    url: &Url,
+) -> Result<Option<ScrapeResult>> {
     let mut url = url.clone();
     let original_url = url.clone();
     if let Some(preferred_host) = &config.preferred_nitter_instance_host {
         url.set_host(Some(preferred_host))
             .context("could not set preferred host")?;
     }
-    let client = crate::scraper::client(config).context("can't get HTTP client")?;
     let dom = client
         .get(url.clone())
         .send()
@@ -131,8 +166,6 @@ pub async fn nitter_scrape(config: &Configuration, url: &Url) -> Result<Option<S
 
 #[cfg(test)]
 mod test {
-    use rand::Rng;
-
     use crate::scraper::{from_url, scrape};
 
     use super::*;
@@ -142,14 +175,12 @@ mod test {
     #[ignore]
     fn test_nitter_scraper() -> Result<()> {
         crate::LOGGER.lock().unwrap().flush();
-        let host = &crate::scraper::nitter::NITTER_INSTANCES;
-        let mut rng = rand::thread_rng();
-        let host = &host[rng.gen_range(0..(host.len()))];
+        let host = "nitter.net";
         let tweet = format!(
             r#"https://{}/TheOnion/status/1372594920427491335?s=20"#,
             host
         );
-        let config = Configuration::default();
+        let config = crate::http::fixture("nitter").build();
 
         let scrape = tokio_test::block_on(scrape(&config, &tweet))?.unwrap();
         visit_diff::assert_eq_diff!(ScrapeResult::Ok(ScrapeResultData{
@@ -160,10 +191,10 @@ mod test {
             images: vec![
                 ScrapeImage {
                     url: from_url(url::Url::from_str(
-                        &format!("https://{}/pic/media%2FEwxvzkEXAAMFg7K.jpg%3Fname%3Dorig?s=20", host),
+                        &format!("https://{}/pic/media%2FEwxvzkEXAAMFg7k.jpg%3Fname%3Dorig?s=20", host),
                     )?),
                     camo_url: from_url(url::Url::from_str(
-                        &format!("https://{}/pic/media%2FEwxvzkEXAAMFg7K.jpg%3Fname%3Dorig?s=20", host),
+                        &format!("https://{}/pic/media%2FEwxvzkEXAAMFg7k.jpg%3Fname%3Dorig?s=20", host),
                     )?),
                 }
             ]
@@ -1,18 +1,55 @@
 use std::str::FromStr;
 
-use reqwest::{Client, Url};
+use reqwest::Url;
 
 use crate::camo::camo_url;
+use crate::error::{ErrorKind, ScrapeError};
+use crate::http::Client;
 use crate::scraper::philomena::derpibooru::is_derpibooru;
 use crate::scraper::{from_url, ScrapeImage, ScrapeResult, ScrapeResultData};
+use crate::scraper::{Registry, SiteScraper};
 use crate::Configuration;
 use anyhow::{Context, Result};
 use log::{debug, trace};
 
 mod derpibooru;
 
-pub async fn is_philomena(url: &Url) -> Result<bool> {
-    is_derpibooru(url).await
+pub struct Philomena;
+
+#[async_trait::async_trait]
+impl SiteScraper for Philomena {
+    fn name(&self) -> &'static str {
+        "Philomena"
+    }
+
+    fn priority(&self) -> i32 {
+        50
+    }
+
+    fn canonicalize(&self, url: &mut Url) {
+        derpibooru::canonicalize(url)
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        is_philomena(url)
+    }
+
+    async fn scrape(
+        &self,
+        config: &Configuration,
+        client: &Client,
+        url: &Url,
+    ) -> Result<Option<ScrapeResult>> {
+        philomena_scrape(config, client, url).await
+    }
+}
+
+pub fn register(registry: &mut Registry) {
+    registry.register(Philomena);
+}
+
+pub fn is_philomena(url: &Url) -> bool {
+    is_derpibooru(url)
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Clone)]
@@ -29,19 +66,28 @@ struct PhilomenaApiImageResponse {
     view_url: String,
 }
 
-pub async fn philomena_scrape(config: &Configuration, url: &Url) -> Result<Option<ScrapeResult>> {
+pub async fn philomena_scrape(
+    config: &Configuration,
+    client: &Client,
+    url: &Url,
+) -> Result<Option<ScrapeResult>> {
     trace!("converting philo url to api url");
-    let api_url = if is_derpibooru(url).await? {
+    let api_url = if is_derpibooru(url) {
         derpibooru::url_to_api(url)?
     } else {
-        anyhow::bail!("Tried URL that isn't known philomena")
+        anyhow::bail!(ScrapeError::new(
+            ErrorKind::UnsupportedUrl,
+            "Tried URL that isn't known philomena"
+        ))
     };
     let api_url = match api_url {
-        None => anyhow::bail!("URL did not match and returned empty"),
+        None => anyhow::bail!(ScrapeError::new(
+            ErrorKind::UnsupportedUrl,
+            "URL did not match and returned empty"
+        )),
         Some(v) => v.to_string(),
     };
-    let client = crate::scraper::client(config)?;
-    let resp: PhilomenaApiResponse = make_philomena_api_request(&client, &api_url).await?;
+    let resp: PhilomenaApiResponse = make_philomena_api_request(client, &api_url).await?;
     let image = resp.image;
     let image_view = Url::from_str(&image.view_url)?;
     let description = image.description;
@@ -182,13 +228,10 @@ mod test {
                 },
             )
         ];
-        let config = Configuration::default();
+        let config = crate::http::fixture("philomena").build();
         for url in urls {
             let scrape = tokio_test::block_on(scrape(&config, url.0));
-            let scrape = match scrape {
-                Ok(s) => s,
-                Err(e) => return Err(e),
-            };
+            let scrape = scrape?;
             let mut scrape = match scrape {
                 Some(s) => s,
                 None => anyhow::bail!("got none response from scraper"),
@@ -5,17 +5,40 @@ use log::trace;
 use regex::Regex;
 use reqwest::Url;
 
+use crate::error::{ErrorKind, ScrapeError};
+
 lazy_static::lazy_static! {
     static ref URL_REGEX: Regex = Regex::from_str(r#"^https://derpibooru.org/(images/)?(?P<image_id>\d+).*$"#).expect("failure in setting up essential regex");
 }
 
-pub async fn is_derpibooru(url: &Url) -> Result<bool> {
+pub fn is_derpibooru(url: &Url) -> bool {
     if URL_REGEX.is_match(url.as_str()) {
         trace!("derpibooru matched on URL pattern 1");
-        return Ok(true);
+        return true;
     }
     trace!("derpibooru didn't match on pattern");
-    Ok(false)
+    false
+}
+
+/// Uses the `/images/N` form for image pages and drops the search context of the query
+pub fn canonicalize(url: &mut Url) {
+    if !matches!(
+        url.host_str(),
+        Some("derpibooru.org") | Some("www.derpibooru.org")
+    ) {
+        return;
+    }
+    let id = url.path().trim_start_matches('/');
+    let id = id.strip_prefix("images/").unwrap_or(id);
+    if id.is_empty() || !id.bytes().all(|x| x.is_ascii_digit()) {
+        return;
+    }
+    let path = format!("/images/{}", id);
+    if url.set_host(Some("derpibooru.org")).is_err() || url.set_scheme("https").is_err() {
+        return;
+    }
+    url.set_path(&path);
+    url.set_query(None);
 }
 
 pub fn url_to_api(url: &Url) -> Result<Option<Url>> {
@@ -28,5 +51,30 @@ pub fn url_to_api(url: &Url) -> Result<Option<Url>> {
             }
         }
     }
-    anyhow::bail!("did not match derpibooru URL")
+    anyhow::bail!(ScrapeError::new(
+        ErrorKind::UnsupportedUrl,
+        "did not match derpibooru URL"
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_canonicalize() -> Result<()> {
+        for url in [
+            "https://derpibooru.org/1",
+            "https://derpibooru.org/images/1?q=safe",
+            "http://www.derpibooru.org/images/1",
+        ] {
+            let mut url = Url::from_str(url)?;
+            canonicalize(&mut url);
+            assert_eq!("https://derpibooru.org/images/1", url.as_str());
+        }
+        let mut search = Url::from_str("https://derpibooru.org/search?q=safe")?;
+        canonicalize(&mut search);
+        assert_eq!("https://derpibooru.org/search?q=safe", search.as_str());
+        Ok(())
+    }
 }
@@ -1,3 +1,5 @@
+use crate::http::Client;
+use crate::scraper::{Registry, SiteScraper};
 use crate::scraper::{ScrapeResult, ScrapeResultData};
 use crate::{scraper::ScrapeImage, Configuration};
 use anyhow::Result;
@@ -14,8 +16,41 @@ lazy_static::lazy_static! {
     ]).iter().map(|x| x.to_string()).collect();
 }
 
-pub async fn is_raw(url: &Url, config: &Configuration) -> Result<bool> {
-    let client = crate::scraper::client(config)?;
+pub struct Raw;
+
+#[async_trait::async_trait]
+impl SiteScraper for Raw {
+    fn name(&self) -> &'static str {
+        "Raw"
+    }
+
+    fn priority(&self) -> i32 {
+        70
+    }
+
+    fn matches(&self, _url: &Url) -> bool {
+        false
+    }
+
+    async fn probe(&self, _config: &Configuration, client: &Client, url: &Url) -> Result<bool> {
+        is_raw(client, url).await
+    }
+
+    async fn scrape(
+        &self,
+        config: &Configuration,
+        client: &Client,
+        url: &Url,
+    ) -> Result<Option<ScrapeResult>> {
+        raw_scrape(config, client, url).await
+    }
+}
+
+pub fn register(registry: &mut Registry) {
+    registry.register(Raw);
+}
+
+pub async fn is_raw(client: &Client, url: &Url) -> Result<bool> {
     let res = client.head(url.clone()).send().await?;
     if res.status() == 200 {
         let content_type = res.headers()["content-type"].to_str()?;
@@ -25,7 +60,11 @@ pub async fn is_raw(url: &Url, config: &Configuration) -> Result<bool> {
     }
 }
 
-pub async fn raw_scrape(config: &Configuration, url: &Url) -> Result<Option<ScrapeResult>> {
+pub async fn raw_scrape(
+    config: &Configuration,
+    _client: &Client,
+    url: &Url,
+) -> Result<Option<ScrapeResult>> {
     Ok(Some(ScrapeResult::Ok(ScrapeResultData {
         source_url: Some(super::from_url(url.clone())),
         author_name: None,
@@ -48,12 +87,9 @@ mod test {
     fn test_raw_scraper() -> Result<()> {
         crate::LOGGER.lock().unwrap().flush();
         let url = r#"https://static.manebooru.art/img/view/2021/3/20/4010154.png"#;
-        let config = Configuration::default();
+        let config = crate::http::fixture("raw").build();
         let scrape = tokio_test::block_on(scrape(&config, url));
-        let scrape = match scrape {
-            Ok(s) => s,
-            Err(e) => return Err(e),
-        };
+        let scrape = scrape?;
         let scrape = match scrape {
             Some(s) => s,
             None => anyhow::bail!("got none response from scraper"),
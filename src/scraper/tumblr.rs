@@ -1,9 +1,11 @@
 use log::{debug, trace};
-use reqwest::Client;
 use std::str::FromStr;
 
+use crate::scraper::{Registry, SiteScraper};
 use crate::{
     camo::camo_url,
+    error::{ErrorKind, ScrapeError},
+    http::Client,
     scraper::{from_url, ScrapeImage, ScrapeResult, ScrapeResultData},
     Configuration,
 };
@@ -32,20 +34,59 @@ lazy_static::lazy_static! {
     static ref TUMBLR_SIZES: Vec<u64> = vec![1280, 540, 500, 400, 250, 100, 75];
 }
 
-pub async fn is_tumblr(url: &Url) -> Result<bool> {
+pub struct Tumblr;
+
+#[async_trait::async_trait]
+impl SiteScraper for Tumblr {
+    fn name(&self) -> &'static str {
+        "Tumblr"
+    }
+
+    fn priority(&self) -> i32 {
+        30
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        is_tumblr(url)
+    }
+
+    async fn probe(&self, _config: &Configuration, client: &Client, url: &Url) -> Result<bool> {
+        trace!("tumblr didn't match on regex, trying host resolver");
+        Ok(match url.host_str() {
+            Some(host) => tumblr_domain(client, host).await?,
+            None => false,
+        })
+    }
+
+    /// Blogs on custom domains are recognized by the address of the host alone
+    fn probe_claims_host(&self) -> bool {
+        true
+    }
+
+    async fn scrape(
+        &self,
+        config: &Configuration,
+        client: &Client,
+        url: &Url,
+    ) -> Result<Option<ScrapeResult>> {
+        tumblr_scrape(config, client, url).await
+    }
+}
+
+pub fn register(registry: &mut Registry) {
+    registry.register(Tumblr);
+}
+
+pub fn is_tumblr(url: &Url) -> bool {
     if URL_REGEX.is_match_at(url.as_str(), 0) {
         trace!("tumblr matched on regex URL");
-        return Ok(true);
+        return true;
     }
-    trace!("tumblr didn't match on regex, trying host resolver");
-    Ok(match url.host() {
-        Some(host) => tumblr_domain(host).await?,
-        None => false,
-    })
+    false
 }
 
-async fn tumblr_domain(host: url::Host<&str>) -> Result<bool> {
-    let hosts = dns_lookup::lookup_host(&host.to_string())?;
+async fn tumblr_domain(client: &Client, host: &str) -> Result<bool> {
+    let hosts = client.lookup_host(host).await?;
     trace!("got hosts for URL: {:?}", hosts);
     for host in hosts {
         if TUMBLR_RANGES.iter().any(|net| net.contains(&host)) {
@@ -70,7 +111,11 @@ async fn make_tumblr_api_request(client: &Client, api_url: &str) -> Result<Value
         .context("could not parse tumblr response as json")
 }
 
-pub async fn tumblr_scrape(config: &Configuration, url: &Url) -> Result<Option<ScrapeResult>> {
+pub async fn tumblr_scrape(
+    config: &Configuration,
+    client: &Client,
+    url: &Url,
+) -> Result<Option<ScrapeResult>> {
     trace!("analyzing tumblr url {}", url);
     let post_id = URL_REGEX.captures(url.as_str());
     let post_id = match post_id {
@@ -97,11 +142,15 @@ pub async fn tumblr_scrape(config: &Configuration, url: &Url) -> Result<Option<S
         api_key = api_key
     );
 
-    let client = crate::scraper::client(config)?;
-    let resp: Value = make_tumblr_api_request(&client, &api_url).await?;
+    let resp: Value = make_tumblr_api_request(client, &api_url).await?;
 
     if resp["meta"]["status"] != 200 {
-        anyhow::bail!("tumblr returned non-200 error");
+        let kind = resp["meta"]["status"]
+            .as_u64()
+            .and_then(|x| reqwest::StatusCode::from_u16(x as u16).ok())
+            .map(ErrorKind::from_status)
+            .unwrap_or(ErrorKind::UpstreamError);
+        anyhow::bail!(ScrapeError::new(kind, "tumblr returned non-200 error"));
     }
 
     let resp = &resp["response"]["posts"][0];
@@ -111,7 +160,7 @@ pub async fn tumblr_scrape(config: &Configuration, url: &Url) -> Result<Option<S
             debug!("photo post, sending to photo scraper");
             add_meta(
                 resp.clone(),
-                process_post(PostType::Photo, resp.clone(), config, &client).await?,
+                process_post(PostType::Photo, resp.clone(), config, client).await?,
             )
             .await
         }
@@ -119,7 +168,7 @@ pub async fn tumblr_scrape(config: &Configuration, url: &Url) -> Result<Option<S
             debug!("text post, sending to post scraper");
             add_meta(
                 resp.clone(),
-                process_post(PostType::Text, resp.clone(), config, &client).await?,
+                process_post(PostType::Text, resp.clone(), config, client).await?,
             )
             .await
         }
@@ -307,28 +356,19 @@ async fn url_ok(client: &Client, url: &Url) -> Result<bool> {
 
 #[cfg(test)]
 mod test {
-    use log::warn;
-
     use crate::scraper::scrape;
 
     use super::*;
 
     #[test]
-    #[ignore]
     fn test_tumblr_scraper() -> Result<()> {
         crate::LOGGER.lock().unwrap().flush();
         let url = r#"https://tcn1205.tumblr.com/post/186904081532/in-wonderland"#;
-        let config = Configuration::default();
-        let api_key = config.tumblr_api_key.clone().unwrap_or_default();
-        if config.tumblr_api_key.is_none() && api_key.trim().is_empty() {
-            warn!("Tumblr API key not configured, skipping");
-            return Ok(());
-        }
+        let config = crate::http::fixture("tumblr")
+            .tumblr_api_key(std::env::var("TUMBLR_API_KEY").unwrap_or_default())
+            .build();
         let scrape = tokio_test::block_on(scrape(&config, url));
-        let scrape = match scrape {
-            Ok(s) => s,
-            Err(e) => return Err(e),
-        };
+        let scrape = scrape?;
         let scrape = match scrape {
             Some(s) => s,
             None => anyhow::bail!("got none response from scraper"),
@@ -350,21 +390,14 @@ mod test {
     }
 
     #[test]
-    #[ignore]
     fn test_text_post_tumblr() -> Result<()> {
         crate::LOGGER.lock().unwrap().flush();
         let url = r#"https://witchtaunter.tumblr.com/post/182898769998/yes-this-is-horse"#;
-        let config = Configuration::default();
-        let api_key = config.tumblr_api_key.clone().unwrap_or_default();
-        if config.tumblr_api_key.is_none() && api_key.trim().is_empty() {
-            warn!("Tumblr API key not configured, skipping");
-            return Ok(());
-        }
+        let config = crate::http::fixture("tumblr")
+            .tumblr_api_key(std::env::var("TUMBLR_API_KEY").unwrap_or_default())
+            .build();
         let scrape = tokio_test::block_on(scrape(&config, url));
-        let scrape = match scrape {
-            Ok(s) => s,
-            Err(e) => return Err(e),
-        };
+        let scrape = scrape?;
         let scrape = match scrape {
             Some(s) => s,
             None => anyhow::bail!("got none response from scraper"),
@@ -384,4 +417,29 @@ mod test {
         visit_diff::assert_eq_diff!(expected_result, scrape);
         Ok(())
     }
+
+    #[test]
+    fn test_probe_resolves_through_cassette() -> Result<()> {
+        use crate::http::{ClientPool, ClientSettings};
+        let pool = ClientPool::new();
+        let tumblr = pool.get(&ClientSettings::new(
+            &crate::http::fixture("tumblr").build(),
+        ))?;
+        let url = Url::from_str("https://art.example.com/some-post")?;
+        assert!(tokio_test::block_on(Tumblr.probe(
+            &Configuration::default(),
+            &tumblr,
+            &url
+        ))?);
+        let raw = pool.get(&ClientSettings::new(&crate::http::fixture("raw").build()))?;
+        let url = Url::from_str("https://static.manebooru.art/img/view/2021/3/20/4010154.png")?;
+        assert!(!tokio_test::block_on(Tumblr.probe(
+            &Configuration::default(),
+            &raw,
+            &url
+        ))?);
+        let url = Url::from_str("https://unrecorded.example.com/a.png")?;
+        assert!(tokio_test::block_on(Tumblr.probe(&Configuration::default(), &raw, &url)).is_err());
+        Ok(())
+    }
 }
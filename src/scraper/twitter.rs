@@ -1,7 +1,10 @@
 use std::{ops::Index, str::FromStr};
 
+use crate::error::{ErrorKind, ScrapeError};
+use crate::http::Client;
 use crate::scraper::ScrapeResult;
 use crate::scraper::ScrapeResultData;
+use crate::scraper::{Registry, SiteScraper};
 use crate::{scraper::ScrapeImage, Configuration};
 use anyhow::{Context, Result};
 use log::trace;
@@ -9,6 +12,16 @@ use regex::Regex;
 use serde_json::Value;
 use url::Url;
 
+/// Hosts serving the same tweets as twitter.com
+const TWITTER_HOSTS: &[&str] = &[
+    "twitter.com",
+    "www.twitter.com",
+    "mobile.twitter.com",
+    "x.com",
+    "www.x.com",
+    "mobile.x.com",
+];
+
 const ACTIVATION_URL: &str = "https://api.twitter.com/1.1/guest/activate.json";
 
 lazy_static::lazy_static! {
@@ -20,14 +33,60 @@ lazy_static::lazy_static! {
         .expect("failure in setting up essential regex");
 }
 
-pub async fn is_twitter(url: &Url) -> Result<bool> {
-    if URL_REGEX.is_match_at(url.as_str(), 0) {
-        return Ok(true);
+pub struct Twitter;
+
+#[async_trait::async_trait]
+impl SiteScraper for Twitter {
+    fn name(&self) -> &'static str {
+        "Twitter"
+    }
+
+    fn priority(&self) -> i32 {
+        10
+    }
+
+    fn canonicalize(&self, url: &mut Url) {
+        canonicalize(url)
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        is_twitter(url)
+    }
+
+    async fn scrape(
+        &self,
+        config: &Configuration,
+        client: &Client,
+        url: &Url,
+    ) -> Result<Option<ScrapeResult>> {
+        twitter_scrape(config, client, url).await
+    }
+}
+
+pub fn register(registry: &mut Registry) {
+    registry.register(Twitter);
+}
+
+/// Moves tweets to twitter.com and drops the share parameters (`?s=20&t=...`)
+pub fn canonicalize(url: &mut Url) {
+    let host = match url.host_str() {
+        Some(host) => host,
+        None => return,
+    };
+    if !TWITTER_HOSTS.contains(&host) {
+        return;
+    }
+    if url.set_host(Some("twitter.com")).is_err() || url.set_scheme("https").is_err() {
+        return;
     }
-    Ok(false)
+    url.set_query(None);
 }
 
-async fn twitter_page_request(client: &reqwest::Client, page_url: &str) -> Result<String> {
+pub fn is_twitter(url: &Url) -> bool {
+    URL_REGEX.is_match_at(url.as_str(), 0)
+}
+
+async fn twitter_page_request(client: &Client, page_url: &str) -> Result<String> {
     trace!("making page request: {}", page_url);
     client
         .get(page_url)
@@ -41,7 +100,7 @@ async fn twitter_page_request(client: &reqwest::Client, page_url: &str) -> Resul
         .with_context(|| format!("could not read api data response from {page_url}"))
 }
 
-async fn get_script_data(client: &reqwest::Client, url: &str) -> Result<String> {
+async fn get_script_data(client: &Client, url: &str) -> Result<String> {
     trace!("making script request: {}", url);
     client
         .get(url)
@@ -55,7 +114,7 @@ async fn get_script_data(client: &reqwest::Client, url: &str) -> Result<String>
         .context("could not read script_data response")
 }
 
-async fn get_gt_token(client: &reqwest::Client, bearer: &str) -> Result<String> {
+async fn get_gt_token(client: &Client, bearer: &str) -> Result<String> {
     trace!("making GT activation request");
     let v = client
         .post(ACTIVATION_URL.to_string())
@@ -68,31 +127,25 @@ async fn get_gt_token(client: &reqwest::Client, bearer: &str) -> Result<String>
         .json::<serde_json::Value>()
         .await
         .context("could not read GT response")?;
-    let guest_token = v.as_object().unwrap().get("guest_token");
+    let guest_token = v.get("guest_token");
     match guest_token {
         Some(guest_token) => Ok(match guest_token.as_str() {
             Some(v) => v.to_string(),
-            None => anyhow::bail!("invalid GT in twitter API response"),
+            None => anyhow::bail!(ScrapeError::parse_failed(
+                "invalid GT in twitter API response"
+            )),
         }),
-        None => anyhow::bail!("no GT in twitter API response"),
+        None => anyhow::bail!(ScrapeError::parse_failed("no GT in twitter API response")),
     }
 }
 
-async fn make_api_request(
-    client: &reqwest::Client,
-    url: &str,
-    bearer: &str,
-    gt: &str,
-) -> Result<Value> {
+async fn make_api_request(client: &Client, url: &str, bearer: &str, gt: &str) -> Result<Value> {
     trace!("making api request: {url}");
-    let req = client
+    client
         .get(url)
         .header("Authorization", format!("Bearer {}", bearer))
         .header("x-guest-token", gt)
-        .build()
-        .with_context(|| format!("failed to build client api_request against {url}"))?;
-    client
-        .execute(req)
+        .send()
         .await
         .context("API request failed")?
         .error_for_status()
@@ -102,13 +155,19 @@ async fn make_api_request(
         .context("response is not valid json")
 }
 
-pub async fn twitter_scrape(config: &Configuration, url: &Url) -> Result<Option<ScrapeResult>> {
-    let client = crate::scraper::client(config).context("could not create twitter agent")?;
+pub async fn twitter_scrape(
+    config: &Configuration,
+    client: &Client,
+    url: &Url,
+) -> Result<Option<ScrapeResult>> {
     let (user, status_id) = {
         let caps = URL_REGEX.captures(url.as_str());
         let caps = match caps {
             Some(caps) => caps,
-            None => anyhow::bail!("could not parse tweet url"),
+            None => anyhow::bail!(ScrapeError::new(
+                ErrorKind::UnsupportedUrl,
+                "could not parse tweet url"
+            )),
         };
         (&caps[1].to_string(), &caps[2].to_string())
     };
@@ -121,30 +180,30 @@ pub async fn twitter_scrape(config: &Configuration, url: &Url) -> Result<Option<
 
     let (gt, bearer) = {
         let page_url = page_url.clone();
-        let api_data = twitter_page_request(&client, &page_url)
+        let api_data = twitter_page_request(client, &page_url)
             .await
             .context("initial page request failed")?;
         let script_caps: Option<regex::Captures> = SCRIPT_REGEX.captures(&api_data);
         let script_caps = match script_caps {
             Some(v) => v[1].to_string(),
-            None => anyhow::bail!("could not get script"),
+            None => anyhow::bail!(ScrapeError::parse_failed("could not get script")),
         };
         log::debug!("script_caps: {:?}", script_caps);
-        let script_data = get_script_data(&client, &script_caps)
+        let script_data = get_script_data(client, &script_caps)
             .await
             .context("invalid script_data response")?;
         let bearer_caps = BEARER_REGEX.captures(&script_data);
         let bearer = match bearer_caps {
             Some(v) => v[0].to_string(),
-            None => anyhow::bail!("could not get bearer"),
+            None => anyhow::bail!(ScrapeError::parse_failed("could not get bearer")),
         };
-        let gt = get_gt_token(&client, &bearer)
+        let gt = get_gt_token(client, &bearer)
             .await
             .context("could not get guest token")?;
         (gt, bearer)
     };
 
-    let mut api_response = make_api_request(&client, &api_url, &bearer, &gt)
+    let mut api_response = make_api_request(client, &api_url, &bearer, &gt)
         .await
         .context("invalid api response")?;
     use std::ops::IndexMut;
@@ -202,21 +261,14 @@ mod test {
     use crate::scraper::{from_url, scrape};
     use std::str::FromStr;
 
-    //TODO: fix twitter test & scraper
-    #[test]
-    #[ignore = "twitter is too unstable to test properly atm"]
-    fn test_twitter_scraper() -> Result<()> {
+    /// Scrapes the tweet and compares the result with the tweet of The Onion
+    fn assert_onion_tweet(config: &Configuration, tweet: &str) -> Result<()> {
         crate::LOGGER.lock().unwrap().flush();
-        let tweet = r#"https://twitter.com/theprincessxena/status/1532144541523910658"#;
-        let config = Configuration::default();
         let mut parsed = url::Url::from_str(tweet)?;
         parsed.set_fragment(None);
         parsed.set_query(None);
-        let scrape = tokio_test::block_on(scrape(&config, tweet));
-        let scrape = match scrape {
-            Ok(s) => s,
-            Err(e) => return Err(e),
-        };
+        let scrape = tokio_test::block_on(scrape(config, tweet));
+        let scrape = scrape?;
         let mut scrape = match scrape {
             Some(s) => s,
             None => anyhow::bail!("got none response from scraper"),
@@ -248,4 +300,39 @@ mod test {
         }), scrape);
         Ok(())
     }
+
+    //TODO: fix twitter test & scraper
+    #[test]
+    #[ignore = "twitter is too unstable to test properly atm"]
+    fn test_twitter_scraper() -> Result<()> {
+        let tweet = r#"https://twitter.com/theprincessxena/status/1532144541523910658"#;
+        assert_onion_tweet(&Configuration::default(), tweet)
+    }
+
+    /// Replays the synthetic fixture, see `tests/fixtures/README.md`
+    #[test]
+    fn test_twitter_scraper_replay() -> Result<()> {
+        let tweet = r#"https://twitter.com/TheOnion/status/1372594920427491335?s=20"#;
+        assert_onion_tweet(&crate::http::fixture("twitter").build(), tweet)
+    }
+
+    #[test]
+    fn test_canonicalize() -> Result<()> {
+        for url in [
+            "https://twitter.com/TheOnion/status/1372594920427491335?s=20&t=abc",
+            "https://mobile.twitter.com/TheOnion/status/1372594920427491335",
+            "http://x.com/TheOnion/status/1372594920427491335?s=19",
+        ] {
+            let mut url = url::Url::from_str(url)?;
+            canonicalize(&mut url);
+            assert_eq!(
+                "https://twitter.com/TheOnion/status/1372594920427491335",
+                url.as_str()
+            );
+        }
+        let mut other = url::Url::from_str("https://example.com/status/1?s=20")?;
+        canonicalize(&mut other);
+        assert_eq!("https://example.com/status/1?s=20", other.as_str());
+        Ok(())
+    }
 }
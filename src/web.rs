@@ -1,4 +1,3 @@
-use anyhow::Result;
 use axum::{
     extract::Query,
     http::{self, Request},
@@ -6,22 +5,79 @@ use axum::{
     response::{self, IntoResponse},
     Extension, Json,
 };
-use log::debug;
+use log::{debug, error};
 use std::sync::Arc;
 use tokio::time::Instant;
 
-use crate::{
-    scraper::{self, ScrapeResult},
-    Configuration, ResultCache, State,
-};
+use scraper::{ErrorKind, ScrapeResult, ScrapeResultError};
+
+use crate::cache::Outcome;
+use crate::State;
 
-#[derive(serde::Deserialize, Clone)]
+#[derive(serde::Deserialize, Clone, utoipa::ToSchema)]
 pub struct ScrapeRequest {
-    url: String,
+    /// Post or image to scrape
+    pub url: String,
+    /// Public http(s) URL the finished job is POSTed to, as `/jobs/{id}` would return
+    /// it with the `ScrapeResult` in `result`, ignored by synchronous scrapes
+    pub callback_url: Option<String>,
+    /// Sent by Philomena's forms, ignored
     #[serde(alias = "_method")]
     _method: Option<String>,
 }
 
+impl ScrapeRequest {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            callback_url: None,
+            _method: None,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct BatchRequest {
+    /// Between 1 and `BATCH_MAX_URLS` URLs
+    urls: Vec<String>,
+}
+
+/// Result of one URL of a batch, with the status a single scrape would have answered
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct BatchResult {
+    url: String,
+    status: u16,
+    result: ScrapeResult,
+}
+
+/// Results of a batch in the order of the request
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct BatchResponse {
+    results: Vec<BatchResult>,
+}
+
+/// Query parameters accepted by all scrape methods
+#[derive(serde::Deserialize, Default, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CacheParams {
+    /// `1` or `true` to bypass the result cache, like `Cache-Control: no-cache`
+    refresh: Option<String>,
+}
+
+impl CacheParams {
+    /// True if the client asked to bypass the result cache via `?refresh=1` or
+    /// `Cache-Control: no-cache`
+    pub fn refresh(&self, headers: &http::HeaderMap) -> bool {
+        let no_cache = headers
+            .get_all(http::header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .any(|x| x.trim().eq_ignore_ascii_case("no-cache"));
+        no_cache || matches!(self.refresh.as_deref(), Some("1") | Some("true"))
+    }
+}
+
 pub async fn latency<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
     let uri = req.uri().clone();
     debug!("Incoming Request {}", uri);
@@ -36,82 +92,280 @@ pub async fn latency<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
 
     res.headers_mut().append(
         "x-time-taken",
-        axum::http::HeaderValue::from_str(&*time_taken).unwrap(),
+        axum::http::HeaderValue::from_str(&time_taken).unwrap(),
     );
 
     res
 }
 
-pub async fn origin_check<B>(
-    req: Request<B>,
-    state: Arc<State>,
-    next: Next<B>,
-) -> std::result::Result<impl response::IntoResponse, http::StatusCode> {
-    let origin = req.headers().get("Origin").map(|x| x.to_str()).transpose();
-    match origin {
-        Ok(origin) => {
-            if state.is_allowed_origin(origin) {
-                Ok(next.run(req).await)
-            } else {
-                Err(http::StatusCode::NOT_FOUND)
-            }
-        }
-        Err(_) => Err(http::StatusCode::INTERNAL_SERVER_ERROR),
-    }
-}
-
+#[utoipa::path(
+    post,
+    path = "/images/scrape",
+    request_body = ScrapeRequest,
+    params(CacheParams),
+    responses((status = 200, description = "Scraped", body = ScrapeResult))
+)]
 pub async fn scrape_post(
     Json(scrape_req): Json<ScrapeRequest>,
+    Query(params): Query<CacheParams>,
+    headers: http::HeaderMap,
     Extension(state): Extension<Arc<State>>,
 ) -> response::Response<String> {
-    match scrape_inner(&state.config, state.result_cache.clone(), scrape_req).await {
-        Ok(v) => v,
-        Err(_) => todo!(),
-    }
+    scrape_response(&state, scrape_req, params.refresh(&headers)).await
 }
 
+#[utoipa::path(
+    get,
+    path = "/images/scrape",
+    params(
+        ("url" = String, Query, description = "Post or image to scrape"),
+        ("_csrf_token" = Option<String>, Query, description = "CSRF token, unless sent in the `x-csrf-token` header"),
+        CacheParams,
+    ),
+    responses((status = 200, description = "Scraped", body = ScrapeResult))
+)]
 pub async fn scrape(
     Query(scrape_req): Query<ScrapeRequest>,
+    Query(params): Query<CacheParams>,
+    headers: http::HeaderMap,
     Extension(state): Extension<Arc<State>>,
 ) -> response::Response<String> {
-    match scrape_inner(&state.config, state.result_cache.clone(), scrape_req).await {
-        Ok(v) => v,
-        Err(_) => todo!(),
+    scrape_response(&state, scrape_req, params.refresh(&headers)).await
+}
+
+/// Scrapes a batch of URLs
+///
+/// Up to `BATCH_MAX_URLS` URLs are scraped, `BATCH_CONCURRENCY` at a time, the results
+/// are in the order of the request.
+#[utoipa::path(
+    post,
+    path = "/images/scrape/batch",
+    request_body = BatchRequest,
+    params(CacheParams),
+    responses(
+        (status = 200, description = "Scraped, each result with the status `/images/scrape` would have answered with", body = BatchResponse),
+        (status = 400, description = "Empty or too large batch", body = ScrapeResultError),
+    )
+)]
+pub async fn scrape_batch(
+    Json(batch): Json<BatchRequest>,
+    Query(params): Query<CacheParams>,
+    headers: http::HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+) -> response::Response<String> {
+    use futures_util::stream::{self, StreamExt};
+    let max = state.config.batch_max_urls;
+    if batch.urls.is_empty() || batch.urls.len() > max {
+        return json_response(
+            http::StatusCode::BAD_REQUEST,
+            &ScrapeResultError::new(
+                ErrorKind::InvalidUrl,
+                format!("a batch must have between 1 and {} URLs", max),
+            ),
+        );
+    }
+    let refresh = params.refresh(&headers);
+    let state = &state;
+    let results: Vec<BatchResult> = stream::iter(batch.urls)
+        .map(|url| async move {
+            let (status, result) =
+                scrape_inner(state, ScrapeRequest::new(url.clone()), refresh).await;
+            BatchResult {
+                url,
+                status: status.as_u16(),
+                result,
+            }
+        })
+        .buffered(state.config.batch_concurrency.max(1))
+        .collect()
+        .await;
+    json_response(http::StatusCode::OK, &BatchResponse { results })
+}
+
+async fn scrape_response(
+    state: &State,
+    scrape_req: ScrapeRequest,
+    refresh: bool,
+) -> response::Response<String> {
+    let (status, res) = scrape_inner(state, scrape_req, refresh).await;
+    if state.config.legacy_status_codes {
+        json_response(http::StatusCode::OK, &res)
+    } else {
+        json_response(status, &res)
     }
 }
 
 pub async fn scrape_inner(
-    config: &Configuration,
-    request_cache: ResultCache,
+    state: &State,
     scrape_req: ScrapeRequest,
-) -> Result<response::Response<String>> {
-    let url = scrape_req.url.clone();
-    let res: std::result::Result<Option<ScrapeResult>, Arc<anyhow::Error>> = request_cache
-        .try_get_with(scrape_req.url, scraper::scrape(config, &url))
-        .await;
-    let res = match res {
-        Ok(r) => r,
-        Err(e) => {
-            let e = ScrapeResult::from_err(e);
-            return Ok(response::Response::builder()
-                .status(http::StatusCode::OK)
-                .header(http::header::CONTENT_TYPE, "application/json")
-                .body(serde_json::to_string(&e)?)?);
+    refresh: bool,
+) -> (http::StatusCode, ScrapeResult) {
+    let url = cache_key(state, scrape_req.url);
+    let clients = if refresh {
+        state.clients.refreshing()
+    } else {
+        state.clients.clone()
+    };
+    let scrape = async {
+        match scraper::REGISTRY
+            .scrape(&state.config.scraper, &clients, &url)
+            .await
+        {
+            Ok(Some(res)) => Outcome::Found(res),
+            Ok(None) => Outcome::Empty,
+            Err(e) => {
+                if ErrorKind::from_error(&e) == ErrorKind::Internal {
+                    error!("internal error while scraping {}: {:?}", url, e);
+                }
+                Outcome::Failed(ScrapeResultError::from_error(&e))
+            }
         }
     };
-    let res = match res {
-        Some(res) => res,
-        None => {
-            return Ok(response::Response::builder()
-                .status(http::StatusCode::OK)
-                .header(http::header::CONTENT_TYPE, "application/json")
-                .body(serde_json::to_string(&ScrapeResult::Err(
-                    "URL invalid".to_string().into(),
-                ))?)?);
+    match state
+        .result_cache
+        .get_or_scrape(&url, refresh, scrape)
+        .await
+    {
+        Outcome::Found(res) => (http::StatusCode::OK, res),
+        Outcome::Empty => (
+            status_for(ErrorKind::UnsupportedUrl),
+            ScrapeResult::Err(ScrapeResultError::new(
+                ErrorKind::UnsupportedUrl,
+                "URL invalid",
+            )),
+        ),
+        Outcome::Failed(e) => (status_for(e.code), ScrapeResult::Err(e)),
+    }
+}
+
+/// Canonical form of the URL, unparseable URLs are kept as is and fail when scraped
+pub fn cache_key(state: &State, url: String) -> String {
+    match scraper::REGISTRY.canonicalize(&state.config.scraper, &url) {
+        Ok(canonical) => canonical.to_string(),
+        Err(_) => url,
+    }
+}
+
+pub fn status_for(code: ErrorKind) -> http::StatusCode {
+    match code {
+        ErrorKind::InvalidUrl => http::StatusCode::BAD_REQUEST,
+        ErrorKind::UnsupportedUrl => http::StatusCode::UNPROCESSABLE_ENTITY,
+        ErrorKind::Timeout => http::StatusCode::GATEWAY_TIMEOUT,
+        ErrorKind::RateLimited => http::StatusCode::TOO_MANY_REQUESTS,
+        ErrorKind::Forbidden => http::StatusCode::FORBIDDEN,
+        ErrorKind::Unauthorized => http::StatusCode::UNAUTHORIZED,
+        ErrorKind::NotFound => http::StatusCode::NOT_FOUND,
+        ErrorKind::UpstreamNotFound
+        | ErrorKind::UpstreamRateLimited
+        | ErrorKind::AuthRequired
+        | ErrorKind::UpstreamError
+        | ErrorKind::ParseFailed => http::StatusCode::BAD_GATEWAY,
+        ErrorKind::Internal => http::StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub fn json_response<T: serde::Serialize>(
+    status: http::StatusCode,
+    body: &T,
+) -> response::Response<String> {
+    let (status, body) = match serde_json::to_string(body) {
+        Ok(body) => (status, body),
+        Err(e) => {
+            error!("could not serialize response: {}", e);
+            (
+                http::StatusCode::INTERNAL_SERVER_ERROR,
+                r#"{"errors":["could not serialize response"],"code":"internal"}"#.to_string(),
+            )
         }
     };
-    Ok(response::Response::builder()
-        .status(http::StatusCode::OK)
-        .header(http::header::CONTENT_TYPE, "application/json")
-        .body(serde_json::to_string(&res)?)?)
+    let mut res = response::Response::new(body);
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_status_codes() {
+        assert_eq!(
+            http::StatusCode::UNPROCESSABLE_ENTITY,
+            status_for(ErrorKind::UnsupportedUrl)
+        );
+        assert_eq!(
+            http::StatusCode::BAD_GATEWAY,
+            status_for(ErrorKind::UpstreamNotFound)
+        );
+        assert_eq!(
+            http::StatusCode::GATEWAY_TIMEOUT,
+            status_for(ErrorKind::Timeout)
+        );
+        assert_eq!(
+            http::StatusCode::TOO_MANY_REQUESTS,
+            status_for(ErrorKind::RateLimited)
+        );
+        let res = json_response(
+            http::StatusCode::INTERNAL_SERVER_ERROR,
+            &ScrapeResult::Err(ScrapeResultError::new(ErrorKind::Internal, "oops")),
+        );
+        assert_eq!(http::StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        assert_eq!(r#"{"errors":["oops"],"code":"internal"}"#, res.body());
+    }
+
+    #[test]
+    fn test_batch_keeps_order() -> anyhow::Result<()> {
+        let state = Arc::new(State::new(crate::Configuration {
+            batch_max_urls: 3,
+            ..crate::Configuration::default()
+        })?);
+        let batch = |urls: &[&str]| {
+            let batch = BatchRequest {
+                urls: urls.iter().map(|x| x.to_string()).collect(),
+            };
+            tokio_test::block_on(scrape_batch(
+                Json(batch),
+                Query(CacheParams::default()),
+                http::HeaderMap::new(),
+                Extension(state.clone()),
+            ))
+        };
+        let res = batch(&["not a url", "", "also not a url"]);
+        assert_eq!(http::StatusCode::OK, res.status());
+        let body: serde_json::Value = serde_json::from_str(res.body())?;
+        let urls: Vec<_> = body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| (x["url"].as_str().unwrap(), x["status"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(
+            vec![("not a url", 400), ("", 400), ("also not a url", 400)],
+            urls
+        );
+        assert_eq!("invalid_url", body["results"][0]["result"]["code"]);
+        let res = batch(&["a", "b", "c", "d"]);
+        assert_eq!(http::StatusCode::BAD_REQUEST, res.status());
+        assert_eq!(http::StatusCode::BAD_REQUEST, batch(&[]).status());
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh_bypass() {
+        let mut headers = http::HeaderMap::new();
+        assert!(!CacheParams::default().refresh(&headers));
+        let params = CacheParams {
+            refresh: Some("1".to_string()),
+        };
+        assert!(params.refresh(&headers));
+        headers.insert(
+            http::header::CACHE_CONTROL,
+            http::HeaderValue::from_static("max-age=0, no-cache"),
+        );
+        assert!(CacheParams::default().refresh(&headers));
+    }
 }
@@ -0,0 +1,421 @@
+//! Delivery of finished jobs to their `callback_url`
+//!
+//! The finished [`Job`] is POSTed as JSON, exactly as `/jobs/{id}` would return it, so
+//! its `result` holds the `ScrapeResult` and `http_status` the status a synchronous
+//! scrape would have answered with. The header `X-Scraper-Signature: sha256=<hex>`
+//! carries the HMAC-SHA256 of the body keyed with `WEBHOOK_SECRET`. Deliveries failing
+//! with a network error, 408, 429 or a 5xx status are retried with exponential backoff.
+//! The queue is bounded, deliveries that don't fit are dropped and the job has to be
+//! polled.
+//!
+//! Callbacks may only reach public addresses: IP literals are checked on submission and
+//! host names are resolved and checked before each delivery, which then connects to the
+//! checked address. Hosts listed in `WEBHOOK_ALLOWED_HOSTS` are exempt, if it is set
+//! callbacks may only go to these hosts.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac, NewMac};
+use log::{debug, warn};
+use reqwest::StatusCode;
+use sha2::Sha256;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::jobs::Job;
+use crate::Configuration;
+
+pub const SIGNATURE_HEADER: &str = "x-scraper-signature";
+
+#[derive(Debug)]
+struct Delivery {
+    url: url::Url,
+    body: String,
+}
+
+#[derive(Clone, Copy)]
+struct Policy {
+    attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+#[derive(Clone)]
+pub struct Webhooks {
+    /// None if no `WEBHOOK_SECRET` is configured
+    secret: Option<Arc<String>>,
+    queue: mpsc::Sender<Delivery>,
+    receiver: Arc<Mutex<mpsc::Receiver<Delivery>>>,
+    policy: Policy,
+    timeout: Duration,
+    /// Lowercase hosts callbacks are restricted to, empty to allow all public hosts
+    allowed_hosts: Arc<Vec<String>>,
+}
+
+impl Webhooks {
+    pub fn new(config: &Configuration) -> Self {
+        let (queue, receiver) = mpsc::channel(config.webhook_queue_size.max(1));
+        Self {
+            secret: config.webhook_secret.clone().map(Arc::new),
+            queue,
+            receiver: Arc::new(Mutex::new(receiver)),
+            policy: Policy {
+                attempts: config.webhook_attempts.max(1),
+                backoff: Duration::from_millis(config.webhook_backoff_ms),
+                max_backoff: Duration::from_secs(60),
+            },
+            timeout: Duration::from_secs(config.webhook_timeout),
+            allowed_hosts: Arc::new(
+                config
+                    .webhook_allowed_hosts
+                    .as_deref()
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|x| x.trim().to_ascii_lowercase())
+                    .filter(|x| !x.is_empty())
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    fn is_allowed_host(&self, host: &str) -> bool {
+        self.allowed_hosts
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(host))
+    }
+
+    /// Parses a submitted `callback_url`, refusing those that may not be delivered to
+    pub fn callback_url(&self, callback: &str) -> Result<url::Url, &'static str> {
+        let url = match url::Url::parse(callback) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+            _ => return Err("callback_url must be an http or https URL"),
+        };
+        let host = url.host_str().unwrap_or_default();
+        if !self.allowed_hosts.is_empty() {
+            return match self.is_allowed_host(host) {
+                true => Ok(url),
+                false => Err("host of callback_url is not allowed"),
+            };
+        }
+        let private = match url.host() {
+            Some(url::Host::Ipv4(ip)) => !is_public(IpAddr::V4(ip)),
+            Some(url::Host::Ipv6(ip)) => !is_public(IpAddr::V6(ip)),
+            Some(url::Host::Domain(domain)) => {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                domain == "localhost" || domain.ends_with(".localhost")
+            }
+            None => true,
+        };
+        match private {
+            true => Err("callback_url must point to a public address"),
+            false => Ok(url),
+        }
+    }
+
+    fn client_builder(&self) -> reqwest::ClientBuilder {
+        reqwest::Client::builder()
+            .timeout(self.timeout)
+            .redirect(reqwest::redirect::Policy::none())
+    }
+
+    /// Client connecting to a checked public address of the URL's host, the shared one
+    /// if the host needs no lookup or is allowed explicitly
+    async fn client_for(
+        &self,
+        shared: &reqwest::Client,
+        url: &url::Url,
+    ) -> anyhow::Result<reqwest::Client> {
+        let host = match url.host() {
+            Some(url::Host::Domain(host)) if !self.is_allowed_host(host) => host,
+            _ => return Ok(shared.clone()),
+        };
+        let port = url.port_or_known_default().unwrap_or_default();
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+        if let Some(addr) = addrs.iter().find(|x| !is_public(x.ip())) {
+            anyhow::bail!("{} resolves to the non-public address {}", host, addr.ip());
+        }
+        match addrs.first() {
+            Some(addr) => Ok(self.client_builder().resolve(host, *addr).build()?),
+            None => anyhow::bail!("{} does not resolve", host),
+        }
+    }
+
+    /// Starts the workers delivering the queue, must be called within the runtime
+    pub fn spawn_workers(&self, workers: usize) -> anyhow::Result<()> {
+        let client = self.client_builder().build()?;
+        for _ in 0..workers.max(1) {
+            let webhooks = self.clone();
+            let client = client.clone();
+            tokio::spawn(async move {
+                loop {
+                    let delivery = webhooks.receiver.lock().await.recv().await;
+                    match delivery {
+                        Some(delivery) => webhooks.deliver(&client, delivery).await,
+                        None => break,
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Queues the job for delivery, returns false if the queue is full
+    pub fn enqueue(&self, url: url::Url, job: &Job) -> bool {
+        let body = match serde_json::to_string(job) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("could not serialize job {}: {}", job.id, e);
+                return false;
+            }
+        };
+        match self.queue.try_send(Delivery { url, body }) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("dropping webhook for job {}: {}", job.id, e);
+                false
+            }
+        }
+    }
+
+    async fn deliver(&self, client: &reqwest::Client, delivery: Delivery) {
+        let secret = match &self.secret {
+            Some(secret) => secret,
+            None => return,
+        };
+        let client = match self.client_for(client, &delivery.url).await {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("refusing webhook delivery: {}", e);
+                return;
+            }
+        };
+        let signature = sign(secret, &delivery.body);
+        let mut backoff = self.policy.backoff;
+        for attempt in 1..=self.policy.attempts {
+            let res = client
+                .post(delivery.url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .body(delivery.body.clone())
+                .send()
+                .await;
+            let retry = match res {
+                Ok(resp) if resp.status().is_success() => {
+                    debug!(
+                        "delivered webhook to {}",
+                        delivery.url.host_str().unwrap_or_default()
+                    );
+                    return;
+                }
+                Ok(resp) => {
+                    let status = resp.status();
+                    warn!("webhook attempt {} answered with {}", attempt, status);
+                    status.is_server_error()
+                        || status == StatusCode::REQUEST_TIMEOUT
+                        || status == StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(e) => {
+                    warn!("webhook attempt {} failed: {}", attempt, e.without_url());
+                    true
+                }
+            };
+            if !retry || attempt == self.policy.attempts {
+                break;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.policy.max_backoff);
+        }
+        warn!(
+            "giving up on webhook to {}",
+            delivery.url.host_str().unwrap_or_default()
+        );
+    }
+}
+
+/// Whether the address is reachable on the internet, rather than reserved for the
+/// host itself, private networks or special purposes
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        || a >= 240
+        // shared address space of carrier grade NAT
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        // benchmarking
+        || (a == 198 && (b == 18 || b == 19)))
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // unique local
+        || (first & 0xfe00) == 0xfc00
+        // link local
+        || (first & 0xffc0) == 0xfe80
+        // documentation
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+/// Value of the signature header for the body
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts any key size");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jobs::JobStatus;
+    use axum::{http, routing::post, Extension, Router};
+    use scraper::ScrapeResult;
+
+    type Received = Arc<std::sync::Mutex<Vec<(String, String)>>>;
+
+    /// Fails the first request with 503, records and accepts all further ones
+    async fn receive(
+        headers: http::HeaderMap,
+        body: String,
+        Extension(received): Extension<Received>,
+    ) -> http::StatusCode {
+        let mut received = received.lock().unwrap();
+        let signature = headers[SIGNATURE_HEADER].to_str().unwrap().to_string();
+        received.push((signature, body));
+        if received.len() == 1 {
+            http::StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            http::StatusCode::OK
+        }
+    }
+
+    #[test]
+    fn test_delivery_is_signed_and_retried() {
+        let received = Received::default();
+        let config = Configuration {
+            webhook_secret: Some("secret".to_string()),
+            webhook_allowed_hosts: Some("127.0.0.1".to_string()),
+            webhook_backoff_ms: 10,
+            ..Configuration::default()
+        };
+        let job = Job {
+            id: "1".to_string(),
+            url: "https://example.com/a.png".to_string(),
+            status: JobStatus::Done {
+                http_status: 200,
+                result: ScrapeResult::None,
+            },
+        };
+        tokio_test::block_on(async {
+            let app = Router::new()
+                .route("/hook", post(receive))
+                .layer(Extension(received.clone()));
+            let server =
+                axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+            let url = url::Url::parse(&format!("http://{}/hook", server.local_addr())).unwrap();
+            tokio::spawn(server);
+            let webhooks = Webhooks::new(&config);
+            webhooks.spawn_workers(1).unwrap();
+            assert!(webhooks.enqueue(url, &job));
+            for _ in 0..100 {
+                if received.lock().unwrap().len() >= 2 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        let received = received.lock().unwrap();
+        assert_eq!(2, received.len());
+        let (signature, body) = &received[1];
+        assert_eq!(&serde_json::to_string(&job).unwrap(), body);
+        assert_eq!(&sign("secret", body), signature);
+    }
+
+    #[test]
+    fn test_full_queue_drops() {
+        let webhooks = Webhooks::new(&Configuration {
+            webhook_queue_size: 1,
+            ..Configuration::default()
+        });
+        let job = Job {
+            id: "1".to_string(),
+            url: "https://example.com/a.png".to_string(),
+            status: JobStatus::Pending,
+        };
+        let url = url::Url::parse("http://127.0.0.1:1/hook").unwrap();
+        assert!(webhooks.enqueue(url.clone(), &job));
+        assert!(!webhooks.enqueue(url, &job));
+    }
+
+    #[test]
+    fn test_callback_must_be_public() {
+        let webhooks = Webhooks::new(&Configuration::default());
+        for url in [
+            "https://hooks.example.com/scraper",
+            "http://93.184.216.34/hook",
+            "https://[2606:2800:220:1::1]/hook",
+        ] {
+            assert!(webhooks.callback_url(url).is_ok(), "{}", url);
+        }
+        for url in [
+            "ftp://hooks.example.com/scraper",
+            "/hook",
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "http://10.0.0.5/hook",
+            "http://192.168.1.1/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/hook",
+            "http://0.0.0.0/hook",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[fe80::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+        ] {
+            assert!(webhooks.callback_url(url).is_err(), "{}", url);
+        }
+        let webhooks = Webhooks::new(&Configuration {
+            webhook_allowed_hosts: Some("hooks.internal, 127.0.0.1".to_string()),
+            ..Configuration::default()
+        });
+        assert!(webhooks
+            .callback_url("http://hooks.internal/scraper")
+            .is_ok());
+        assert!(webhooks.callback_url("http://127.0.0.1:8080/hook").is_ok());
+        assert!(webhooks
+            .callback_url("https://hooks.example.com/scraper")
+            .is_err());
+    }
+
+    #[test]
+    fn test_resolved_addresses_are_checked() {
+        let webhooks = Webhooks::new(&Configuration::default());
+        let shared = webhooks.client_builder().build().unwrap();
+        let url = url::Url::parse("http://localhost:1/hook").unwrap();
+        let client = tokio_test::block_on(webhooks.client_for(&shared, &url));
+        assert!(client.is_err());
+    }
+}